@@ -8,7 +8,7 @@ use quad_net::web_socket::WebSocket;
 use sapp_jsutils::JsObject;
 use uuid::Uuid;
 
-use hydro_common::{AnimationData, EntityAddMessage, MessageC2S, MessageS2C, PlayerInputMessage, RunningAnimation};
+use hydro_common::{AnimationData, EntityAddMessage, EntityVisuals, MessageC2S, MessageS2C, PlayerInputMessage, RunningAnimation, TileAppearance, TileCell, TileDataValue, WorldBackground};
 use hydro_common::pos::{CHUNK_SIZE, ChunkOffset, ChunkPosition};
 
 #[macroquad::main("hydro")]
@@ -19,32 +19,66 @@ async fn main() {
     let mut connection = Connection::connect("ws://localhost:8080/ws");
     let mut world = World {
         chunks: HashMap::new(),
+        tile_data: HashMap::new(),
+        layers: Vec::new(),
         entities: HashMap::new(),
     };
     let mut camera_position = Vec2 { x: 0., y: 0. };
     let mut content = None;
+    let mut clock_start = 0.;
+    let mut background = WorldBackground::default();
+    let mut background_textures: HashMap<String, Texture2D> = HashMap::new();
     let mut connected = false;
     loop {
         for message in connection.read_messages() {
             match message {
                 MessageS2C::LoadChunk(position, tiles, entities) => {
                     world.chunks.insert(position, tiles);
+                    world.tile_data.remove(&position);
                     for entity in entities {
                         world.add_entity(entity);
                     }
                 }
                 MessageS2C::UnloadChunk(position, entities) => {
                     world.chunks.remove(&position);
+                    world.tile_data.remove(&position);
                     for entity in entities {
                         world.entities.remove(&entity);
                     }
                 }
-                MessageS2C::SetTile(position, tileset, tile) => {
+                MessageS2C::SetTile(position, layer, tile) => {
                     let (chunk_position, chunk_offset) = position.to_chunk_position();
                     if let Some(chunk) = world.chunks.get_mut(&chunk_position) {
-                        chunk.entry(tileset).or_insert_with(|| vec![0; (CHUNK_SIZE * CHUNK_SIZE) as usize])[chunk_offset.index()] = tile;
+                        if let Some(tile_data) = world.tile_data.get_mut(&chunk_position) {
+                            tile_data.remove(&(layer.clone(), chunk_offset.index() as u16));
+                        }
+                        chunk.entry(layer).or_insert_with(|| vec![TileCell::EMPTY; (CHUNK_SIZE * CHUNK_SIZE) as usize])[chunk_offset.index()] = tile;
+                    }
+                }
+                MessageS2C::SetTiles(chunk_position, layers) => {
+                    if let Some(chunk) = world.chunks.get_mut(&chunk_position) {
+                        for (layer, cells) in layers {
+                            let tiles = chunk.entry(layer.clone()).or_insert_with(|| vec![TileCell::EMPTY; (CHUNK_SIZE * CHUNK_SIZE) as usize]);
+                            for (index, cell) in cells {
+                                tiles[index as usize] = cell;
+                                if let Some(tile_data) = world.tile_data.get_mut(&chunk_position) {
+                                    tile_data.remove(&(layer.clone(), index));
+                                }
+                            }
+                        }
                     }
                 }
+                MessageS2C::SetTileData(position, layer, key, value) => {
+                    let (chunk_position, chunk_offset) = position.to_chunk_position();
+                    let tile_data = world.tile_data.entry(chunk_position).or_default().entry((layer, chunk_offset.index() as u16)).or_default();
+                    match value {
+                        Some(value) => tile_data.insert(key, value),
+                        None => tile_data.remove(&key),
+                    };
+                }
+                MessageS2C::SetWorldLayers(layers) => {
+                    world.layers = layers;
+                }
                 MessageS2C::AddEntity(entity) => {
                     world.add_entity(entity);
                 }
@@ -53,41 +87,48 @@ async fn main() {
                 }
                 MessageS2C::UpdateEntityPosition(id, position) => {
                     if let Some(entity) = world.entities.get_mut(&id) {
-                        entity.0 = Vec2::new(position.x as f32, position.y as f32);
+                        entity.position = Vec2::new(position.x as f32, position.y as f32);
                     }
                 }
                 MessageS2C::UpdateEntityAnimation(id, animation) => {
                     if let Some(entity) = world.entities.get_mut(&id) {
-                        entity.2 = animation;
+                        entity.animation_start = get_time() - animation.time as f64;
+                        entity.animation = animation;
+                    }
+                }
+                MessageS2C::UpdateEntityVisuals(id, visuals) => {
+                    if let Some(entity) = world.entities.get_mut(&id) {
+                        entity.visuals = visuals;
+                    }
+                }
+                MessageS2C::SetEntityParent(id, parent) => {
+                    if let Some(entity) = world.entities.get_mut(&id) {
+                        entity.parent = parent.map(|(parent, offset)| (parent, Vec2::new(offset.x as f32, offset.y as f32)));
                     }
                 }
                 MessageS2C::LoadContent(content_msg) => {
                     connected = true;
+                    clock_start = get_time() - content_msg.time;
                     unsafe { set_title_name(JsObject::string(content_msg.name.as_str())); }
                     content = Some(Content {
-                        tilesets: content_msg.tilesets.into_iter().map(|(key, value)| {
+                        tilesets: content_msg.tilesets.into_iter().map(|value| {
                             let texture = Texture2D::from_file_with_format(value.asset.as_slice(), Some(ImageFormat::Png));
                             texture.set_filter(FilterMode::Nearest);
-                            (key, TileSetContent {
+                            TileSetContent {
                                 asset: texture,
                                 size: value.size,
                                 tiles: value.tiles,
-                            })
+                            }
+                        }).collect(),
+                        sprite_sheets: content_msg.sprite_sheets.into_iter().map(|(key, value)| {
+                            let texture = Texture2D::from_file_with_format(value.as_slice(), Some(ImageFormat::Png));
+                            texture.set_filter(FilterMode::Nearest);
+                            (key, texture)
                         }).collect(),
                         entities: content_msg.entities.into_iter().map(|(key, value)| {
                             (key, EntityContent {
                                 size: value.size,
-                                animations: value.animations.into_iter().map(|(key, value)| {
-                                    let texture = Texture2D::from_file_with_format(value.image.as_slice(), Some(ImageFormat::Png));
-                                    texture.set_filter(FilterMode::Nearest);
-                                    (key, AnimationDataTextured {
-                                        image: texture,
-                                        period: value.period,
-                                        count: value.count,
-                                        flip: value.flip,
-                                        looped: value.looped,
-                                    })
-                                }).collect(),
+                                animations: value.animations,
                             })
                         }).collect(),
                     });
@@ -95,6 +136,17 @@ async fn main() {
                 MessageS2C::CameraInfo(position) => {
                     camera_position = Vec2::new(position.x as f32, position.y as f32);
                 }
+                MessageS2C::SetBackground(new_background, images) => {
+                    background_textures.retain(|name, _| new_background.images.iter().any(|image| image.image == *name));
+                    for (name, data) in images {
+                        background_textures.entry(name).or_insert_with(|| {
+                            let texture = Texture2D::from_file_with_format(data.as_slice(), Some(ImageFormat::Png));
+                            texture.set_filter(FilterMode::Nearest);
+                            texture
+                        });
+                    }
+                    background = new_background;
+                }
             }
         }
 
@@ -138,39 +190,55 @@ async fn main() {
                 mouse_position: hydro_common::pos::Vec2{x: mouse.x as f64, y: mouse.y as f64 },
             }));
         }
-        clear_background(RED);
+        clear_background(Color::from_rgba(background.color.0, background.color.1, background.color.2, background.color.3));
         set_camera(&camera);
+        let view_size = Vec2::new(2. / camera.zoom.x.abs(), 2. / camera.zoom.y.abs());
+        let view = Rect::new(camera.target.x - view_size.x / 2., camera.target.y - view_size.y / 2., view_size.x, view_size.y);
+        for image in &background.images {
+            let Some(texture) = background_textures.get(&image.image) else {
+                continue;
+            };
+            let size = Vec2::new(image.size.x as f32, image.size.y as f32);
+            if size.x <= 0. || size.y <= 0. {
+                continue;
+            }
+            //parallax 1 keeps the image fixed in the world, 0 keeps it fixed on screen
+            let base = Vec2::new(image.position.x as f32, image.position.y as f32) + camera.target * (Vec2::ONE - Vec2::new(image.parallax.0, image.parallax.1));
+            let (x_start, x_count) = if image.repeat_x {
+                (base.x + ((view.x - base.x) / size.x).floor() * size.x, (view.w / size.x).ceil() as i32 + 1)
+            } else {
+                (base.x, 1)
+            };
+            let (y_start, y_count) = if image.repeat_y {
+                (base.y + ((view.y - base.y) / size.y).floor() * size.y, (view.h / size.y).ceil() as i32 + 1)
+            } else {
+                (base.y, 1)
+            };
+            for x in 0..x_count {
+                for y in 0..y_count {
+                    draw_texture_ex(texture, x_start + x as f32 * size.x, y_start + y as f32 * size.y, Color::from_rgba(image.tint.0, image.tint.1, image.tint.2, image.tint.3), DrawTextureParams {
+                        dest_size: Some(size),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
         if let Some(content) = &content {
-            for (position, tiles) in &world.chunks {
-                for (tileset, tiles) in tiles {
-                    let tileset = content.tilesets.get(tileset).unwrap();
-                    for x in 0..CHUNK_SIZE {
-                        for y in 0..CHUNK_SIZE {
-                            if let Some(tileset_position) = tileset.tiles.get(tiles[ChunkOffset { x: x as u8, y: y as u8 }.index()] as usize).unwrap() {
-                                let source = Rect::new((tileset_position.0 * tileset.size) as f32, (tileset_position.1 * tileset.size) as f32, tileset.size as f32, tileset.size as f32);
-                                draw_texture_ex(&tileset.asset, x as f32 + (position.x as i32 * CHUNK_SIZE) as f32, y as f32 + (position.y as i32 * CHUNK_SIZE) as f32, WHITE, DrawTextureParams {
-                                    dest_size: Some(Vec2::new(1., 1.)),
-                                    source: Some(source),
-                                    ..Default::default()
-                                });
-                            }
-                        }
-                    }
+            let time = get_time() - clock_start;
+            let mut layers: Vec<_> = world.layers.iter().collect();
+            layers.sort_by_key(|(_, z)| *z);
+            let mut entities: Vec<_> = world.entities.iter().filter(|(_, entity)| entity.visuals.visible).collect();
+            entities.sort_by_key(|(_, entity)| entity.visuals.z);
+            let mut entities = entities.into_iter().peekable();
+            //entities with the same z as a layer are drawn on top of it
+            for (layer, z) in layers {
+                while let Some((id, entity)) = entities.next_if(|(_, entity)| entity.visuals.z < *z) {
+                    world.draw_entity(content, id, entity);
                 }
+                world.draw_tile_layer(content, layer, time);
             }
-            for (position, entity_type, animation) in world.entities.values() {
-                let entity = content.entities.get(entity_type).unwrap();
-                let animation_data = entity.animations.get(&animation.id).unwrap();
-                let image_size = animation_data.image.size();
-                let frame = (animation.time / animation_data.period as f32) as usize;
-                let frame = if animation_data.looped { frame % animation_data.count as usize } else { frame.min(animation_data.count as usize - 1) };
-                let width = image_size.x / animation_data.count as f32;
-                draw_texture_ex(&animation_data.image, position.x, position.y, WHITE, DrawTextureParams {
-                    dest_size: Some(Vec2::new(entity.size.0 as f32, entity.size.1 as f32)),
-                    source: Some(Rect::new(width * frame as f32, 0., width, image_size.y)),
-                    flip_y: animation_data.flip,
-                    ..Default::default()
-                });
+            for (id, entity) in entities {
+                world.draw_entity(content, id, entity);
             }
         }
 
@@ -184,32 +252,110 @@ async fn main() {
     }
 }
 pub struct Content {
-    pub tilesets: HashMap<String, TileSetContent>,
+    pub tilesets: Vec<TileSetContent>,
+    pub sprite_sheets: HashMap<String, Texture2D>,
     pub entities: HashMap<String, EntityContent>,
 }
 pub struct TileSetContent {
     pub asset: Texture2D,
     pub size: u8,
-    pub tiles: Vec<Option<(u8, u8)>>,
+    pub tiles: Vec<Option<TileAppearance>>,
 }
 pub struct EntityContent {
-    pub animations: HashMap<String, AnimationDataTextured>,
+    pub animations: HashMap<String, AnimationData>,
     pub size: (f64, f64),
 }
-pub struct AnimationDataTextured {
-    pub image: Texture2D,
-    pub count: u16,
-    pub period: f64,
-    pub looped: bool,
-    pub flip: bool,
+pub struct ClientEntity {
+    pub position: Vec2,
+    pub entity_type: String,
+    pub animation: RunningAnimation,
+    pub animation_start: f64,
+    pub parent: Option<(Uuid, Vec2)>,
+    pub visuals: EntityVisuals,
 }
 pub struct World {
-    chunks: HashMap<ChunkPosition, HashMap<String, Vec<u32>>>,
-    entities: HashMap<Uuid, (Vec2, String, RunningAnimation)>,
+    chunks: HashMap<ChunkPosition, HashMap<String, Vec<TileCell>>>,
+    /// Replicated tile data by chunk, then by layer and cell index.
+    tile_data: HashMap<ChunkPosition, HashMap<(String, u16), HashMap<String, TileDataValue>>>,
+    layers: Vec<(String, i32)>,
+    entities: HashMap<Uuid, ClientEntity>,
 }
 impl World {
+    pub fn draw_tile_layer(&self, content: &Content, layer: &str, time: f64) {
+        for (position, layers) in &self.chunks {
+            let Some(tiles) = layers.get(layer) else {
+                continue;
+            };
+            for x in 0..CHUNK_SIZE {
+                for y in 0..CHUNK_SIZE {
+                    let cell = tiles[ChunkOffset { x: x as u8, y: y as u8 }.index()];
+                    let Some(tileset) = content.tilesets.get(cell.tileset as usize) else {
+                        continue;
+                    };
+                    if let Some(Some(appearance)) = tileset.tiles.get(cell.id as usize) {
+                        let tileset_position = appearance.position_at(time);
+                        let source = Rect::new((tileset_position.0 * tileset.size) as f32, (tileset_position.1 * tileset.size) as f32, tileset.size as f32, tileset.size as f32);
+                        let (rotation, flip_x, flip_y) = cell.orientation.draw_params();
+                        draw_texture_ex(&tileset.asset, x as f32 + (position.x as i32 * CHUNK_SIZE) as f32, y as f32 + (position.y as i32 * CHUNK_SIZE) as f32, WHITE, DrawTextureParams {
+                            dest_size: Some(Vec2::new(1., 1.)),
+                            source: Some(source),
+                            rotation,
+                            flip_x,
+                            flip_y,
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+    }
+    pub fn draw_entity(&self, content: &Content, id: &Uuid, client_entity: &ClientEntity) {
+        let Some(position) = self.entity_position(id) else {
+            return;
+        };
+        let visuals = &client_entity.visuals;
+        let animation = &client_entity.animation;
+        let entity = content.entities.get(&client_entity.entity_type).unwrap();
+        let animation_data = entity.animations.get(&animation.id).unwrap();
+        let Some(frame) = animation_data.frame_at(get_time() - client_entity.animation_start) else {
+            return;
+        };
+        let sheet = content.sprite_sheets.get(&animation_data.sheet).unwrap();
+        let size = Vec2::new(entity.size.0 as f32, entity.size.1 as f32);
+        let scaled_size = size * Vec2::new(visuals.scale.0, visuals.scale.1);
+        let position = position + (size - scaled_size) / 2.;
+        draw_texture_ex(sheet, position.x, position.y, Color::from_rgba(visuals.tint.0, visuals.tint.1, visuals.tint.2, visuals.tint.3), DrawTextureParams {
+            dest_size: Some(scaled_size),
+            source: Some(Rect::new(frame.x as f32, frame.y as f32, frame.w as f32, frame.h as f32)),
+            rotation: visuals.rotation,
+            flip_x: visuals.flip_x,
+            flip_y: animation_data.flip ^ visuals.flip_y,
+            ..Default::default()
+        });
+    }
     pub fn add_entity(&mut self, entity: EntityAddMessage) {
-        self.entities.insert(entity.uuid, (Vec2::new(entity.position.x as f32, entity.position.y as f32), entity.entity_type, entity.animation));
+        self.entities.insert(entity.uuid, ClientEntity {
+            position: Vec2::new(entity.position.x as f32, entity.position.y as f32),
+            entity_type: entity.entity_type,
+            animation_start: get_time() - entity.animation.time as f64,
+            animation: entity.animation,
+            parent: entity.parent.map(|(parent, offset)| (parent, Vec2::new(offset.x as f32, offset.y as f32))),
+            visuals: entity.visuals,
+        });
+    }
+    pub fn entity_position(&self, id: &Uuid) -> Option<Vec2> {
+        let mut entity = self.entities.get(id)?;
+        let mut position = Vec2::ZERO;
+        for _ in 0..16 {
+            match entity.parent.and_then(|(parent, offset)| Some((self.entities.get(&parent)?, offset))) {
+                Some((parent, offset)) => {
+                    position += offset;
+                    entity = parent;
+                }
+                None => break,
+            }
+        }
+        Some(position + entity.position)
     }
 }
 
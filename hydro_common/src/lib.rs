@@ -37,15 +37,93 @@ pub struct PlayerInputMessage {
 }
 #[derive(Serialize, Deserialize)]
 pub enum MessageS2C {
-    LoadChunk(ChunkPosition, HashMap<String, Vec<u32>>, Vec<EntityAddMessage>),
+    /// Tiles are grouped by layer name.
+    LoadChunk(ChunkPosition, HashMap<String, Vec<TileCell>>, Vec<EntityAddMessage>),
     UnloadChunk(ChunkPosition, Vec<Uuid>),
-    SetTile(TilePosition, String, u32),
+    SetTile(TilePosition, String, TileCell),
+    /// Changes of one chunk during a tick, cells are grouped by layer and addressed by `ChunkOffset::index`.
+    SetTiles(ChunkPosition, HashMap<String, Vec<(u16, TileCell)>>),
+    /// Sets a replicated key of a tile's data in a layer, `None` removes it.
+    SetTileData(TilePosition, String, String, Option<TileDataValue>),
+    /// Tile layers of the current world with their z index, in declaration order.
+    SetWorldLayers(Vec<(String, i32)>),
     AddEntity(EntityAddMessage),
     RemoveEntity(Uuid),
     UpdateEntityPosition(Uuid, Vec2),
     UpdateEntityAnimation(Uuid, RunningAnimation),
+    SetEntityParent(Uuid, Option<(Uuid, Vec2)>),
+    UpdateEntityVisuals(Uuid, EntityVisuals),
     LoadContent(LoadContentMessage),
     CameraInfo(Vec2),
+    SetBackground(WorldBackground, HashMap<String, Vec<u8>>),
+}
+/// Plain value of tile data, which can be replicated and saved.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum TileDataValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Table(Vec<(TileDataValue, TileDataValue)>),
+}
+/// Tile of one layer cell, `tileset` indexes `LoadContentMessage::tilesets`.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub struct TileCell {
+    pub tileset: u16,
+    pub id: u32,
+    pub orientation: TileOrientation,
+}
+impl TileCell {
+    pub const EMPTY: TileCell = TileCell { tileset: u16::MAX, id: 0, orientation: TileOrientation(0) };
+
+    pub fn is_empty(&self) -> bool {
+        self.tileset == u16::MAX
+    }
+}
+impl Default for TileCell {
+    fn default() -> Self {
+        TileCell::EMPTY
+    }
+}
+/// Tiled style orientation flags, the diagonal flip is applied first, then the horizontal and vertical ones.
+#[derive(Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TileOrientation(pub u8);
+impl TileOrientation {
+    pub const FLIP_HORIZONTAL: u8 = 1;
+    pub const FLIP_VERTICAL: u8 = 2;
+    pub const FLIP_DIAGONAL: u8 = 4;
+
+    pub fn from_flags(flip_horizontal: bool, flip_vertical: bool, flip_diagonal: bool) -> Self {
+        TileOrientation((flip_horizontal as u8) * Self::FLIP_HORIZONTAL | (flip_vertical as u8) * Self::FLIP_VERTICAL | (flip_diagonal as u8) * Self::FLIP_DIAGONAL)
+    }
+    /// Clockwise rotation by `quarter_turns`, optionally followed by flips.
+    pub fn from_rotation(quarter_turns: u8, flip_x: bool, flip_y: bool) -> Self {
+        let rotation = match quarter_turns % 4 {
+            0 => TileOrientation(0),
+            1 => TileOrientation(Self::FLIP_DIAGONAL | Self::FLIP_HORIZONTAL),
+            2 => TileOrientation(Self::FLIP_HORIZONTAL | Self::FLIP_VERTICAL),
+            _ => TileOrientation(Self::FLIP_DIAGONAL | Self::FLIP_VERTICAL),
+        };
+        TileOrientation(rotation.0 ^ (flip_x as u8) * Self::FLIP_HORIZONTAL ^ (flip_y as u8) * Self::FLIP_VERTICAL)
+    }
+    pub fn flip_horizontal(&self) -> bool {
+        self.0 & Self::FLIP_HORIZONTAL != 0
+    }
+    pub fn flip_vertical(&self) -> bool {
+        self.0 & Self::FLIP_VERTICAL != 0
+    }
+    pub fn flip_diagonal(&self) -> bool {
+        self.0 & Self::FLIP_DIAGONAL != 0
+    }
+    /// Rotation in radians and horizontal/vertical flip that render this orientation
+    /// when the flips are applied before the rotation.
+    pub fn draw_params(&self) -> (f32, bool, bool) {
+        if self.flip_diagonal() {
+            (std::f32::consts::FRAC_PI_2, self.flip_vertical(), !self.flip_horizontal())
+        } else {
+            (0., self.flip_horizontal(), self.flip_vertical())
+        }
+    }
 }
 #[derive(Serialize, Deserialize)]
 pub struct EntityAddMessage {
@@ -53,18 +131,96 @@ pub struct EntityAddMessage {
     pub entity_type: String,
     pub position: Vec2,
     pub animation: RunningAnimation,
+    pub parent: Option<(Uuid, Vec2)>,
+    pub visuals: EntityVisuals,
+}
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct EntityVisuals {
+    pub rotation: f32,
+    pub scale: (f32, f32),
+    pub tint: (u8, u8, u8, u8),
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub z: i32,
+    pub visible: bool,
+}
+impl Default for EntityVisuals {
+    fn default() -> Self {
+        EntityVisuals {
+            rotation: 0.,
+            scale: (1., 1.),
+            tint: (255, 255, 255, 255),
+            flip_x: false,
+            flip_y: false,
+            z: 0,
+            visible: true,
+        }
+    }
+}
+/// Drawn behind the tile layers, images are referenced by name and their png data travels with the message.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorldBackground {
+    pub color: (u8, u8, u8, u8),
+    pub images: Vec<BackgroundImage>,
+}
+impl Default for WorldBackground {
+    fn default() -> Self {
+        WorldBackground {
+            color: (0, 0, 0, 255),
+            images: Vec::new(),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackgroundImage {
+    pub image: String,
+    pub position: Vec2,
+    pub size: Vec2,
+    /// How much the image moves with the world, 1 scrolls with the tiles and 0 stays fixed on screen.
+    pub parallax: (f32, f32),
+    pub repeat_x: bool,
+    pub repeat_y: bool,
+    pub tint: (u8, u8, u8, u8),
 }
 #[derive(Serialize, Deserialize)]
 pub struct LoadContentMessage {
     pub name: String,
-    pub tilesets: HashMap<String, TileSetContentMessage>,
+    pub tilesets: Vec<TileSetContentMessage>,
+    pub sprite_sheets: HashMap<String, Vec<u8>>,
     pub entities: HashMap<String, EntityContentMessage>,
+    pub time: f64,
 }
 #[derive(Serialize, Deserialize)]
 pub struct TileSetContentMessage {
+    pub name: String,
     pub asset: Vec<u8>,
     pub size: u8,
-    pub tiles: Vec<Option<(u8, u8)>>,
+    pub tiles: Vec<Option<TileAppearance>>,
+}
+/// Atlas positions a tile cycles through, a single frame for static tiles.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TileAppearance {
+    pub frames: Vec<TileFrame>,
+}
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct TileFrame {
+    pub position: (u8, u8),
+    pub duration: f64,
+}
+impl TileAppearance {
+    pub fn position_at(&self, time: f64) -> (u8, u8) {
+        let cycle: f64 = self.frames.iter().map(|frame| frame.duration).sum();
+        if self.frames.len() > 1 && cycle > 0. {
+            let mut remaining = time.rem_euclid(cycle);
+            for frame in &self.frames {
+                if remaining < frame.duration {
+                    return frame.position;
+                }
+                remaining -= frame.duration;
+            }
+        }
+        self.frames[0].position
+    }
 }
 #[derive(Serialize, Deserialize)]
 pub struct EntityContentMessage {
@@ -73,14 +229,205 @@ pub struct EntityContentMessage {
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AnimationData {
-    pub image: Vec<u8>,
-    pub count: u16,
-    pub period: f64,
+    pub sheet: String,
+    pub frames: Vec<AnimationFrame>,
     pub looped: bool,
+    pub ping_pong: bool,
     pub flip: bool,
 }
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct AnimationFrame {
+    pub x: u16,
+    pub y: u16,
+    pub w: u16,
+    pub h: u16,
+    pub duration: f64,
+}
+impl AnimationData {
+    /// Number of frames shown during one cycle, ping-pong animations play inner frames twice.
+    pub fn step_count(&self) -> usize {
+        let count = self.frames.len();
+        if self.ping_pong && count > 1 {
+            if self.looped { count * 2 - 2 } else { count * 2 - 1 }
+        } else {
+            count
+        }
+    }
+    /// Frame shown at `step`, an animation without frames stays on frame 0.
+    pub fn frame_of_step(&self, step: usize) -> usize {
+        let count = self.frames.len();
+        let step = step % self.step_count().max(1);
+        if step < count { step } else { (count * 2).saturating_sub(2 + step) }
+    }
+    pub fn cycle_duration(&self) -> f64 {
+        (0..self.step_count()).map(|step| self.frames[self.frame_of_step(step)].duration).sum()
+    }
+    /// Step playing at `time`, counted across loops. Non-looping animations stay on their last step.
+    pub fn step_at(&self, time: f64) -> usize {
+        let steps = self.step_count();
+        if steps == 0 {
+            return 0;
+        }
+        let cycle = self.cycle_duration();
+        if cycle <= 0. {
+            return if self.looped { 0 } else { steps - 1 };
+        }
+        let (loops, mut remaining) = if self.looped {
+            ((time / cycle).floor().max(0.) as usize, time.rem_euclid(cycle))
+        } else {
+            if time >= cycle {
+                return steps - 1;
+            }
+            (0, time.max(0.))
+        };
+        for step in 0..steps {
+            let duration = self.frames[self.frame_of_step(step)].duration;
+            if remaining < duration {
+                return loops * steps + step;
+            }
+            remaining -= duration;
+        }
+        loops * steps + steps - 1
+    }
+    pub fn frame_at(&self, time: f64) -> Option<&AnimationFrame> {
+        self.frames.get(self.frame_of_step(self.step_at(time)))
+    }
+    pub fn is_finished(&self, time: f64) -> bool {
+        !self.looped && time >= self.cycle_duration()
+    }
+}
 #[derive(Serialize, Deserialize)]
 pub struct RunningAnimation {
     pub id: String,
     pub time: f32,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the corner at (`x`, `y`) of a unit tile ends up, applying the flags in Tiled's order.
+    fn transform(orientation: TileOrientation, (mut x, mut y): (i32, i32)) -> (i32, i32) {
+        if orientation.flip_diagonal() {
+            (x, y) = (y, x);
+        }
+        if orientation.flip_horizontal() {
+            x = 1 - x;
+        }
+        if orientation.flip_vertical() {
+            y = 1 - y;
+        }
+        (x, y)
+    }
+    /// Where the corner ends up when drawn with `draw_params`: flips first, then a clockwise rotation.
+    fn draw_transform(orientation: TileOrientation, (mut x, mut y): (i32, i32)) -> (i32, i32) {
+        let (rotation, flip_x, flip_y) = orientation.draw_params();
+        if flip_x {
+            x = 1 - x;
+        }
+        if flip_y {
+            y = 1 - y;
+        }
+        for _ in 0..(rotation / std::f32::consts::FRAC_PI_2).round() as i32 {
+            (x, y) = (1 - y, x);
+        }
+        (x, y)
+    }
+    const CORNERS: [(i32, i32); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
+
+    #[test]
+    fn from_rotation_turns_clockwise() {
+        for quarter_turns in 0..4 {
+            let orientation = TileOrientation::from_rotation(quarter_turns, false, false);
+            for corner in CORNERS {
+                let mut expected = corner;
+                for _ in 0..quarter_turns {
+                    expected = (1 - expected.1, expected.0);
+                }
+                assert_eq!(transform(orientation, corner), expected, "{} quarter turns", quarter_turns);
+            }
+        }
+        assert!(TileOrientation::from_rotation(4, false, false) == TileOrientation::default());
+    }
+    #[test]
+    fn from_rotation_flips_after_turning() {
+        for quarter_turns in 0..4 {
+            let turned = TileOrientation::from_rotation(quarter_turns, false, false);
+            let flipped = TileOrientation::from_rotation(quarter_turns, true, true);
+            for corner in CORNERS {
+                let (x, y) = transform(turned, corner);
+                assert_eq!(transform(flipped, corner), (1 - x, 1 - y));
+            }
+        }
+    }
+    #[test]
+    fn draw_params_render_every_orientation() {
+        for flags in 0..8 {
+            let orientation = TileOrientation(flags);
+            for corner in CORNERS {
+                assert_eq!(draw_transform(orientation, corner), transform(orientation, corner), "flags {}", flags);
+            }
+        }
+    }
+    #[test]
+    fn from_flags_sets_each_flag() {
+        let orientation = TileOrientation::from_flags(true, false, true);
+        assert!(orientation.flip_horizontal() && !orientation.flip_vertical() && orientation.flip_diagonal());
+    }
+
+    fn animation(durations: &[f64], looped: bool, ping_pong: bool) -> AnimationData {
+        AnimationData {
+            sheet: "sheet".to_string(),
+            frames: durations.iter().map(|duration| AnimationFrame { x: 0, y: 0, w: 1, h: 1, duration: *duration }).collect(),
+            looped,
+            ping_pong,
+            flip: false,
+        }
+    }
+
+    #[test]
+    fn frame_of_step_wraps_around() {
+        let animation = animation(&[1., 1., 1.], true, false);
+        let frames: Vec<usize> = (0..7).map(|step| animation.frame_of_step(step)).collect();
+        assert_eq!(frames, [0, 1, 2, 0, 1, 2, 0]);
+    }
+    #[test]
+    fn looped_ping_pong_doesnt_repeat_the_first_frame() {
+        let animation = animation(&[1., 1., 1., 1.], true, true);
+        assert_eq!(animation.step_count(), 6);
+        let frames: Vec<usize> = (0..8).map(|step| animation.frame_of_step(step)).collect();
+        assert_eq!(frames, [0, 1, 2, 3, 2, 1, 0, 1]);
+    }
+    #[test]
+    fn ping_pong_without_loop_ends_on_the_first_frame() {
+        let animation = animation(&[1., 2., 3.], false, true);
+        assert_eq!(animation.step_count(), 5);
+        let frames: Vec<usize> = (0..5).map(|step| animation.frame_of_step(step)).collect();
+        assert_eq!(frames, [0, 1, 2, 1, 0]);
+        assert_eq!(animation.cycle_duration(), 9.);
+    }
+    #[test]
+    fn cycle_duration_sums_frame_durations() {
+        assert_eq!(animation(&[0.5, 0.25, 1.], true, false).cycle_duration(), 1.75);
+        assert_eq!(animation(&[0.5, 0.25, 1.], true, true).cycle_duration(), 2.);
+        assert_eq!(animation(&[2.], true, true).cycle_duration(), 2.);
+    }
+    #[test]
+    fn animation_without_frames_stays_on_frame_zero() {
+        let animation = animation(&[], true, true);
+        assert_eq!(animation.step_count(), 0);
+        assert_eq!(animation.frame_of_step(0), 0);
+        assert_eq!(animation.frame_of_step(5), 0);
+        assert_eq!(animation.cycle_duration(), 0.);
+        assert!(animation.frame_at(1.).is_none());
+    }
+    #[test]
+    fn step_at_counts_loops_and_holds_the_last_step() {
+        let looped = animation(&[1., 2.], true, false);
+        assert_eq!(looped.step_at(0.5), 0);
+        assert_eq!(looped.step_at(1.5), 1);
+        assert_eq!(looped.step_at(3.5), 2);
+        let once = animation(&[1., 2.], false, false);
+        assert_eq!(once.step_at(10.), 1);
+        assert!(once.is_finished(3.));
+    }
+}
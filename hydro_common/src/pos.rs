@@ -16,6 +16,12 @@ impl ChunkOffset {
     pub fn index(&self) -> usize {
         self.x as usize + (self.y as usize * CHUNK_SIZE as usize)
     }
+    pub fn from_index(index: usize) -> Self {
+        ChunkOffset {
+            x: (index % CHUNK_SIZE as usize) as u8,
+            y: (index / CHUNK_SIZE as usize) as u8,
+        }
+    }
 }
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct TilePosition {
@@ -35,6 +41,12 @@ impl TilePosition {
             }
         )
     }
+    pub fn from_chunk_position(position: ChunkPosition, offset: ChunkOffset) -> Self {
+        TilePosition {
+            x: position.x as i32 * CHUNK_SIZE + offset.x as i32,
+            y: position.y as i32 * CHUNK_SIZE + offset.y as i32,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Copy, Clone)]
@@ -1,8 +1,12 @@
+register_collision_layer("solid")
+register_collision_layer("player")
+set_layers_collide("player", "solid", true)
+
 register_tileset("main", {
     tiles = {
         {
             id = "stone",
-            collision_mask = 1,
+            collision_mask = "solid",
             asset_pos = {x=8,y=5}
         }
     },
@@ -36,7 +40,7 @@ register_entity("player", {
             y=0,
             w=0.4,
             h=0.8,
-            mask=1
+            layer="player"
         }
     },
     animations={
@@ -54,22 +58,24 @@ register_entity("player", {
 
 register_event("start", function()
     --local pos1 = pos(0, 0, "lobby")
-    --tileset("main"):set_at(pos1, "stone")
-    --tileset("main"):set_at(pos(1, 1, "lobby"), "stone")
-    --print(tileset("main"):get_data_at(pos1).aaa)
+    --layer("ground"):set_at(pos1, "main:stone")
+    --layer("ground"):set_at(pos(1, 1, "lobby"), "main:stone")
+    --print(layer("ground"):get_data_at(pos1).aaa)
 
     --schedule(function()
     --    print("here")
     --    return 1
     --end, 3)
 
-    local layers = {}
-    layers["Tile Layer 1"] = "aaa"
-    layers["Tile Layer 2"] = "aaa"
-    load_map_into_world("map.tmx", "lobby", layers)
+    create_world("lobby", {
+        settings = {
+            spawn = pos(-2, 0, "lobby")
+        }
+    })
+    load_map_into_world("map.tmx", "lobby")
 end)
 register_event("join", function(client)
-    local player_entity = spawn("player", pos(-2, 0, "lobby"))
+    local player_entity = spawn("player", get_world_properties("lobby").spawn)
     client:set_camera_entity(player_entity)
     client.controlling_entity = player_entity
 end)
@@ -88,7 +94,7 @@ register_event("tick", function()
             new_position = new_position:move(0, 1*speed)
         end
 
-        _,client.controlling_entity.position = client.controlling_entity:get_collider("main"):test_sweep(1, new_position)
+        _,client.controlling_entity.position = client.controlling_entity:get_collider("main"):test_sweep(new_position)
         new_position = client.controlling_entity.position
 
         if client:is_key_down(keys.a) then
@@ -97,7 +103,7 @@ register_event("tick", function()
         if client:is_key_down(keys.d) then
             new_position = new_position:move(1*speed, 0)
         end
-        _,client.controlling_entity.position = client.controlling_entity:get_collider("main"):test_sweep(1, new_position)
+        _,client.controlling_entity.position = client.controlling_entity:get_collider("main"):test_sweep(new_position)
     end
 end)
 register_event("load_chunk", function(position)
@@ -0,0 +1,119 @@
+use std::cmp::Ordering;
+
+use hydro_common::{AnimationData, AnimationFrame};
+use serde_json::Value;
+
+pub struct AsepriteSheet {
+    pub image: String,
+    frames: Vec<AnimationFrame>,
+    tags: Vec<AsepriteTag>,
+}
+struct AsepriteTag {
+    name: String,
+    from: usize,
+    to: usize,
+    direction: String,
+}
+impl AsepriteSheet {
+    /// Loads `assets/<file>.json` exported by Aseprite, in either array or hash frame layout.
+    pub fn load(file: &str) -> mlua::Result<Self> {
+        let path = format!("assets/{}.json", file);
+        let text = std::fs::read_to_string(&path).map_err(|error| mlua::Error::runtime(format!("couldn't read {}: {}", path, error)))?;
+        let json: Value = serde_json::from_str(&text).map_err(|error| mlua::Error::runtime(format!("invalid aseprite file {}: {}", path, error)))?;
+        let invalid = |what: &str| mlua::Error::runtime(format!("invalid aseprite file {}: {}", path, what));
+
+        let frames = match &json["frames"] {
+            Value::Array(frames) => frames.iter().collect::<Vec<_>>(),
+            Value::Object(frames) => {
+                let mut frames: Vec<_> = frames.iter().collect();
+                frames.sort_by(|(first, _), (second, _)| natural_cmp(first, second));
+                frames.into_iter().map(|(_, frame)| frame).collect()
+            }
+            _ => return Err(invalid("missing frames")),
+        };
+        let frames = frames.into_iter().map(|frame| {
+            let rect = &frame["frame"];
+            let field = |name: &str| rect[name].as_u64().map(|value| value as u16).ok_or_else(|| invalid("frame rectangle"));
+            Ok(AnimationFrame {
+                x: field("x")?,
+                y: field("y")?,
+                w: field("w")?,
+                h: field("h")?,
+                duration: frame["duration"].as_f64().unwrap_or(100.) / 1000.,
+            })
+        }).collect::<mlua::Result<Vec<_>>>()?;
+
+        let meta = &json["meta"];
+        let image = meta["image"].as_str().ok_or_else(|| invalid("missing meta.image"))?;
+        let image = image.strip_suffix(".png").unwrap_or(image).to_string();
+        let tags = match meta["frameTags"].as_array() {
+            Some(tags) => tags.iter().map(|tag| {
+                Ok(AsepriteTag {
+                    name: tag["name"].as_str().ok_or_else(|| invalid("tag name"))?.to_string(),
+                    from: tag["from"].as_u64().ok_or_else(|| invalid("tag range"))? as usize,
+                    to: tag["to"].as_u64().ok_or_else(|| invalid("tag range"))? as usize,
+                    direction: tag["direction"].as_str().unwrap_or("forward").to_string(),
+                })
+            }).collect::<mlua::Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        Ok(AsepriteSheet { image, frames, tags })
+    }
+    pub fn tag_names(&self) -> impl Iterator<Item=&str> {
+        self.tags.iter().map(|tag| tag.name.as_str())
+    }
+    /// Builds the animation for `tag`, or for every frame when no tag is given.
+    pub fn animation(&self, tag: Option<&str>, looped: bool, flip: bool) -> mlua::Result<AnimationData> {
+        let (frames, ping_pong) = match tag {
+            Some(name) => {
+                let tag = self.tags.iter().find(|tag| tag.name == name).ok_or_else(|| mlua::Error::runtime(format!("aseprite tag {} doesn't exist", name)))?;
+                let mut frames = self.frames.get(tag.from..=tag.to).ok_or_else(|| mlua::Error::runtime(format!("aseprite tag {} is out of range", name)))?.to_vec();
+                if tag.direction == "reverse" || tag.direction == "pingpong_reverse" {
+                    frames.reverse();
+                }
+                (frames, tag.direction.starts_with("pingpong"))
+            }
+            None => (self.frames.clone(), false),
+        };
+        if frames.is_empty() {
+            return Err(mlua::Error::runtime(format!("aseprite export {} has no frames for this animation", self.image)));
+        }
+        Ok(AnimationData {
+            sheet: self.image.clone(),
+            frames,
+            looped,
+            ping_pong,
+            flip,
+        })
+    }
+}
+/// Compares frame names so that `run 10.aseprite` sorts after `run 9.aseprite`.
+fn natural_cmp(first: &str, second: &str) -> Ordering {
+    let (mut first, mut second) = (first, second);
+    loop {
+        match (first.chars().next(), second.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) if a.is_ascii_digit() && b.is_ascii_digit() => {
+                let a_len = first.find(|c: char| !c.is_ascii_digit()).unwrap_or(first.len());
+                let b_len = second.find(|c: char| !c.is_ascii_digit()).unwrap_or(second.len());
+                let a_number = first[..a_len].trim_start_matches('0');
+                let b_number = second[..b_len].trim_start_matches('0');
+                let ordering = a_number.len().cmp(&b_number.len()).then_with(|| a_number.cmp(b_number));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                first = &first[a_len..];
+                second = &second[b_len..];
+            }
+            (Some(a), Some(b)) => {
+                if a != b {
+                    return a.cmp(&b);
+                }
+                first = &first[a.len_utf8()..];
+                second = &second[b.len_utf8()..];
+            }
+        }
+    }
+}
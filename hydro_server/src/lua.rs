@@ -1,18 +1,21 @@
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::TryRecvError;
 
 use immutable_string::ImmutableString;
-use mlua::{AnyUserData, Error, FromLua, Function, Lua, OwnedAnyUserData, OwnedFunction, Table, UserData, UserDataFields, UserDataMethods, Value};
-use tiled::{ChunkData, LayerType, Properties, PropertyValue, TileLayer};
+use mlua::{AnyUserData, Error, FromLua, Function, Lua, OwnedAnyUserData, OwnedFunction, OwnedTable, Table, UserData, UserDataFields, UserDataMethods, Value};
+use tiled::{ChunkData, LayerType, ObjectData, ObjectShape, Properties, PropertyValue, TileLayer};
 use uuid::Uuid;
 
-use hydro_common::{EntityAddMessage, MessageC2S, MessageS2C, MouseButton, PlayerInputMessage, RunningAnimation};
-use hydro_common::pos::{CHUNK_SIZE, ChunkPosition, TilePosition, Vec2};
+use hydro_common::{BackgroundImage, EntityAddMessage, EntityVisuals, MessageC2S, MessageS2C, MouseButton, PlayerInputMessage, RunningAnimation, TileCell, TileDataValue, TileOrientation, WorldBackground};
+use hydro_common::pos::{ChunkPosition, TilePosition, Vec2};
 
-use crate::{ChunkTileLayer, ClientConnection, Server, ServerPtr};
-use crate::util::AABB;
+use crate::{Chunk, ClientConnection, InitEnvironment, Server, ServerPtr, TileSet, WorldLayer};
+use crate::save;
+use crate::terrain::NEIGHBOURS;
+use crate::util::{AABB, png_size};
 
 pub fn init_lua_functions(lua: &Lua) {
     let globals = lua.globals();
@@ -28,14 +31,45 @@ pub fn init_lua_functions(lua: &Lua) {
         })
     }).unwrap()).unwrap();
 
-    globals.set("tileset", lua.create_function(|_, (tileset): (String)| {
-        Ok(LuaTileSet {
-            tileset: tileset.into(),
-        })
+    globals.set("collision_layer", lua.create_function(|_, mask: CollisionMask| {
+        Ok(mask.0)
     }).unwrap()).unwrap();
 
-    globals.set("spawn", lua.create_function(|lua, (type_id, position): (String, Position)| {
-        Ok(Entity::new(lua, type_id.into(), position))
+    globals.set("layer", lua.create_function(|_, layer: String| {
+        Ok(LuaTileLayer::new(layer.into()))
+    }).unwrap()).unwrap();
+    globals.set("set_world_layers", lua.create_function(|lua, (world, layers): (String, Vec<Table>)| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        for layer in layers {
+            let name: String = layer.get("name")?;
+            server.define_layer(world.as_str().into(), name.into(), Some(layer.get::<_, Option<i32>>("z")?.unwrap_or(WorldLayer::DEFAULT_Z)))?;
+        }
+        Ok(())
+    }).unwrap()).unwrap();
+    globals.set("get_world_layers", lua.create_function(|lua, world: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let layers = lua.create_table()?;
+        if let Some(world) = server.worlds.borrow().get::<ImmutableString>(&world.into()) {
+            for layer in &world.layers {
+                let layer_table = lua.create_table()?;
+                layer_table.set("name", layer.name.to_string())?;
+                layer_table.set("z", layer.z)?;
+                layers.push(layer_table)?;
+            }
+        }
+        Ok(layers)
+    }).unwrap()).unwrap();
+
+    globals.set("spawn", lua.create_function(|lua, (type_id, position, options): (String, Position, Option<Table>)| {
+        let mut visibility = EntityVisibility::default();
+        if let Some(options) = options {
+            visibility.owner = options.get::<_, Option<OwnedAnyUserData>>("owner")?.map(|owner| owner.borrow::<Client>().map(|owner| owner.id)).transpose()?;
+            visibility.owner_only = options.get::<_, Option<bool>>("owner_only")?.unwrap_or(false);
+            if let Some(visible_to) = options.get::<_, Option<Vec<OwnedAnyUserData>>>("visible_to")? {
+                visibility.allowed = Some(visible_to.iter().map(|client| client.borrow::<Client>().map(|client| client.id)).collect::<mlua::Result<_>>()?);
+            }
+        }
+        Ok(Entity::new(lua, type_id.into(), position, visibility, |_| Ok(())))
     }).unwrap()).unwrap();
 
     globals.set("get_entity", lua.create_function(|lua, (id, ): (String,)| {
@@ -44,6 +78,140 @@ pub fn init_lua_functions(lua: &Lua) {
         let id = server.entities.borrow().get(&uuid).cloned();
         Ok(id)
     }).unwrap()).unwrap();
+    globals.set("find_entities", lua.create_function(|lua, query: Table| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let aabb: Option<LuaAABB> = query.get("aabb")?;
+        let radius: Option<f64> = query.get("radius")?;
+        let center: Option<Position> = query.get::<_, Option<Position>>("center")?.or_else(|| aabb.as_ref().map(|aabb| Position {
+            x: aabb.aabb.x + aabb.aabb.w / 2.,
+            y: aabb.aabb.y + aabb.aabb.h / 2.,
+            world: aabb.world.clone(),
+        }));
+        let world: ImmutableString = match (query.get::<_, Option<String>>("world")?, &center) {
+            (Some(world), _) => world.into(),
+            (None, Some(center)) => center.world.clone(),
+            (None, None) => return Err(Error::runtime("entity query needs a world, aabb or center")),
+        };
+        if radius.is_some() && center.is_none() {
+            return Err(Error::runtime("radius query needs a center"));
+        }
+        let entity_type: Option<ImmutableString> = query.get::<_, Option<String>>("type")?.map(|entity_type| entity_type.into());
+        let tag: Option<ImmutableString> = query.get::<_, Option<String>>("tag")?.map(|tag| tag.into());
+        let mut bounds = aabb.as_ref().map(|aabb| aabb.aabb);
+        if let (Some(center), Some(radius)) = (&center, radius) {
+            let circle = AABB { x: center.x - radius, y: center.y - radius, w: radius * 2., h: radius * 2. };
+            bounds = Some(match bounds {
+                Some(bounds) => {
+                    let (x, y) = (bounds.x.max(circle.x), bounds.y.max(circle.y));
+                    AABB { x, y, w: ((bounds.x + bounds.w).min(circle.x + circle.w) - x).max(0.), h: ((bounds.y + bounds.h).min(circle.y + circle.h) - y).max(0.) }
+                }
+                None => circle,
+            });
+        }
+        let mut found = Vec::new();
+        {
+            let worlds = server.worlds.borrow();
+            let Some(world) = worlds.get(&world) else {
+                return Ok(lua.create_table()?);
+            };
+            let chunks: Vec<&Chunk> = match bounds {
+                Some(bounds) => {
+                    let margin = server.entity_registry.entities.values().fold(0f64, |margin, entity_type| margin.max(entity_type.size.0).max(entity_type.size.1));
+                    let from = TilePosition { x: (bounds.x - margin).floor() as i32, y: (bounds.y - margin).floor() as i32 }.to_chunk_position().0;
+                    let to = TilePosition { x: (bounds.x + bounds.w).floor() as i32, y: (bounds.y + bounds.h).floor() as i32 }.to_chunk_position().0;
+                    (from.x..=to.x).flat_map(|x| (from.y..=to.y).map(move |y| ChunkPosition { x, y })).filter_map(|position| world.chunks.get(&position)).collect()
+                }
+                None => world.chunks.values().collect(),
+            };
+            for chunk in chunks {
+                for entity_obj in chunk.entities.values() {
+                    let entity = entity_obj.borrow::<Entity>()?;
+                    if entity_type.as_ref().is_some_and(|entity_type| *entity_type != entity.type_id) {
+                        continue;
+                    }
+                    if tag.as_ref().is_some_and(|tag| !entity.tags.borrow().contains(tag)) {
+                        continue;
+                    }
+                    let entity_bounds = entity.bounds(&server);
+                    if aabb.as_ref().is_some_and(|aabb| !aabb.aabb.collides(entity_bounds)) {
+                        continue;
+                    }
+                    let distance = center.as_ref().map(|center| {
+                        ((entity_bounds.x + entity_bounds.w / 2. - center.x).powi(2) + (entity_bounds.y + entity_bounds.h / 2. - center.y).powi(2)).sqrt()
+                    }).unwrap_or(0.);
+                    if radius.is_some_and(|radius| distance > radius) {
+                        continue;
+                    }
+                    found.push((distance, entity_obj.clone()));
+                }
+            }
+        }
+        if query.get::<_, Option<bool>>("sorted")?.unwrap_or(false) {
+            found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        let entities = lua.create_table()?;
+        for (_, entity) in found {
+            entities.push(entity)?;
+        }
+        Ok(entities)
+    }).unwrap()).unwrap();
+    globals.set("get_entities_with_tag", lua.create_function(|lua, tag: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let entities = server.tagged_entities.borrow().get::<ImmutableString>(&tag.into()).map(|tagged| tagged.values().cloned().collect()).unwrap_or_else(Vec::new);
+        Ok(entities)
+    }).unwrap()).unwrap();
+    globals.set("get_map_objects", lua.create_function(|lua, (world, filter): (String, Option<Table>)| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let world: ImmutableString = world.into();
+        let worlds = server.worlds.borrow();
+        let objects = lua.create_table()?;
+        if let Some(world_data) = worlds.get(&world) {
+            for object in &world_data.map_objects {
+                if filter.as_ref().map(|filter| object.matches(filter)).transpose()?.unwrap_or(true) {
+                    objects.push(object.to_lua(lua, &world)?)?;
+                }
+            }
+        }
+        Ok(objects)
+    }).unwrap()).unwrap();
+    globals.set("set_world_background", lua.create_function(|lua, (world, background): (String, Table)| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let (background, image_data) = background_from_lua(&background)?;
+        server.update_background(world.into(), |world| {
+            world.background = background;
+            world.background_images = image_data;
+        })
+    }).unwrap()).unwrap();
+    globals.set("create_world", lua.create_function(|lua, (name, options): (String, Option<Table>)| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        let name: ImmutableString = name.into();
+        let generator = options.as_ref().map(|options| options.get::<_, Option<OwnedFunction>>("generator")).transpose()?.flatten();
+        let properties = match options.as_ref().map(|options| options.get::<_, Option<OwnedTable>>("settings")).transpose()?.flatten() {
+            Some(settings) => settings,
+            None => lua.create_table()?.into_owned(),
+        };
+        let background = options.as_ref().map(|options| options.get::<_, Option<Table>>("background")).transpose()?.flatten().map(|background| background_from_lua(&background)).transpose()?;
+        server.create_world(name.clone(), generator, properties)?;
+        if let Some((background, image_data)) = background {
+            server.update_background(name, |world| {
+                world.background = background;
+                world.background_images = image_data;
+            })?;
+        }
+        Ok(())
+    }).unwrap()).unwrap();
+    globals.set("delete_world", lua.create_function(|lua, name: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        server.delete_world(&name.into())
+    }).unwrap()).unwrap();
+    globals.set("list_worlds", lua.create_function(|lua, ()| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        Ok(server.world_names().iter().map(|name| name.to_string()).collect::<Vec<_>>())
+    }).unwrap()).unwrap();
+    globals.set("get_world_properties", lua.create_function(|lua, name: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        server.world_properties(&name.into())
+    }).unwrap()).unwrap();
     globals.set("get_client", lua.create_function(|lua, (id, ): (String,)| {
         let uuid = Uuid::parse_str(id.as_str()).map_err(|_| Error::runtime("malformed uuid"))?;
         let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
@@ -69,53 +237,34 @@ pub fn init_lua_functions(lua: &Lua) {
         Ok(())
     }).unwrap()).unwrap();
 
-    globals.set("load_map_into_world", lua.create_function(|lua, (map, world, tilesets): (String, String, Table)|{
+    globals.set("set_random_tick_speed", lua.create_function(|lua, speed: u32| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        server.set_random_tick_speed(speed);
+        Ok(())
+    }).unwrap()).unwrap();
+
+    globals.set("save_world", lua.create_function(|lua, world: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        save::save_world(&server, &world.into())
+    }).unwrap()).unwrap();
+    globals.set("load_world", lua.create_function(|lua, world: String| {
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        save::load_world(&server, lua, &world.into())
+    }).unwrap()).unwrap();
+
+    globals.set("load_map_into_world", lua.create_function(|lua, (map_file, world, tilesets): (String, String, Option<Table>)|{
         let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
         let world: ImmutableString = world.into();
-        let mut map = tiled::Loader::new().load_tmx_map(map).unwrap();
-        for layer in map.layers() {
-            match layer.layer_type(){
-                LayerType::Tiles(tiles) => {
-                    if let Some(tileset) = tilesets.get::<_, String>(layer.name.as_str()).ok().map(|tileset|Into::<ImmutableString>::into(tileset)) {
-                        match tiles{
-                            TileLayer::Finite(finite) => {}
-                            TileLayer::Infinite(infinite) => {
-                                for (pos, chunk) in infinite.chunks() {
-                                    for x in 0..ChunkData::WIDTH {
-                                        for y in 0..ChunkData::HEIGHT {
-                                            let position = TilePosition {
-                                                x: pos.0 * ChunkData::WIDTH as i32 + x as i32,
-                                                y: pos.1 * ChunkData::HEIGHT as i32 + y as i32,
-                                            };
-                                            if let Some(tile) = chunk.get_tile(x as i32, y as i32) {
-                                                server.set_tile(position, world.clone(), tileset.clone(), format!("{}", tile.id()).into())?;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                LayerType::Objects(objects) => {
-                    for object in objects.objects() {
-                        let id = match object.properties.get("Class").unwrap(){
-                            PropertyValue::StringValue(string) => string,
-                            _ => panic!(),
-                        };
-                        let mut entity = Entity::new(lua, id.as_str().into(), Position{
-                            x: object.x as f64,
-                            y: object.y as f64,
-                            world: world.clone(),
-                        }).unwrap();
-                        load_tiled_properties_into_lua_table(lua, &entity.to_ref().nth_user_value::<Table>(2).unwrap(), &object.properties);
-                    }
-                }
-                LayerType::Image(_) => {}
-                LayerType::Group(_) => {}
-            }
+        if !server.world_exists(&world) {
+            return Err(Error::runtime(format!("world {} doesn't exist", world)));
         }
-        Ok(())
+        let map = tiled::Loader::new().load_tmx_map(&map_file).map_err(|error| Error::runtime(format!("couldn't load map {}: {}", map_file, error)))?;
+        let map_tilesets = map.tilesets().iter().map(|tileset| resolve_tiled_tileset(&server, tilesets.as_ref(), tileset)).collect::<mlua::Result<Vec<_>>>()?;
+        let tile_size = (map.tile_width as f32, map.tile_height as f32);
+        if let Some(color) = map.background_color {
+            server.update_background(world.clone(), |world| world.background.color = (color.red, color.green, color.blue, color.alpha))?;
+        }
+        load_map_layers(lua, &server, &world, tilesets.as_ref(), &map_tilesets, map.layers(), (0., 0.), tile_size)
     }).unwrap()).unwrap();
 
     {
@@ -165,75 +314,747 @@ pub fn init_lua_functions(lua: &Lua) {
         globals.set("keys", keys).unwrap();
     }
 }
-pub fn load_tiled_properties_into_lua_table(lua: &Lua, table: &Table, properties: &Properties){
+/// Finds the registered tileset for a Tiled tileset: an explicit entry in the `tilesets` table,
+/// a tileset registered under the same name, or one registered from the same Tiled tileset or image.
+fn resolve_tiled_tileset(server: &Server, overrides: Option<&Table>, tileset: &tiled::Tileset) -> mlua::Result<Option<ImmutableString>> {
+    if let Some(name) = overrides.map(|overrides| overrides.get::<_, Option<String>>(tileset.name.as_str())).transpose()?.flatten() {
+        return Ok(Some(name.into()));
+    }
+    let name = ImmutableString::from(tileset.name.as_str());
+    if server.tile_sets.contains_key(&name) {
+        return Ok(Some(name));
+    }
+    //an explicit `tiled_tileset` name wins over a matching image file
+    for matches in [TileSet::matches_tiled_name, TileSet::matches_tiled_image] {
+        let mut candidates: Vec<String> = server.tile_sets.iter().filter(|(_, registered)| matches(registered, tileset)).map(|(name, _)| name.to_string()).collect();
+        match candidates.len() {
+            0 => continue,
+            1 => return Ok(candidates.pop().map(|name| name.into())),
+            _ => {
+                candidates.sort();
+                return Err(Error::runtime(format!("tiled tileset {} matches tilesets {}, choose one in the tilesets table", tileset.name, candidates.join(", "))));
+            }
+        }
+    }
+    Ok(None)
+}
+/// Loads Tiled layers into `world`. Pixel offsets of layers and their parent groups are accumulated
+/// and converted to whole tiles for tile layers, objects keep the exact offset.
+fn load_map_layers<'map>(lua: &Lua, server: &Server, world: &ImmutableString, tilesets: Option<&Table>, map_tilesets: &[Option<ImmutableString>], layers: impl Iterator<Item=tiled::Layer<'map>>, offset: (f32, f32), tile_size: (f32, f32)) -> mlua::Result<()> {
+    for layer in layers {
+        let offset = (offset.0 + layer.offset_x, offset.1 + layer.offset_y);
+        match layer.layer_type(){
+            LayerType::Tiles(tiles) => {
+                if layer.parallax_x != 1. || layer.parallax_y != 1. {
+                    eprintln!("parallax of tile layer {} is ignored", layer.name);
+                }
+                //a tileset named after the layer overrides the tileset of every tile in it
+                let layer_tileset = tilesets.map(|tilesets| tilesets.get::<_, Option<String>>(layer.name.as_str())).transpose()?.flatten().map(|tileset| Into::<ImmutableString>::into(tileset));
+                let layer_name: ImmutableString = layer.name.as_str().into();
+                //layers are drawn in map order, `z` or `foreground` properties move them relative to entities
+                let z = match (layer.properties.get("z"), layer.properties.get("foreground")) {
+                    (Some(PropertyValue::IntValue(z)), _) => *z,
+                    (Some(_), _) => return Err(Error::runtime(format!("z property of layer {} must be an int", layer.name))),
+                    (None, Some(PropertyValue::BoolValue(true))) => 1,
+                    (None, _) => WorldLayer::DEFAULT_Z,
+                };
+                server.define_layer(world.clone(), layer_name.clone(), Some(z))?;
+                let tile_offset = ((offset.0 / tile_size.0).round() as i32, (offset.1 / tile_size.1).round() as i32);
+                let mut cells = Vec::new();
+                let mut place = |position: TilePosition, tile: tiled::LayerTile| -> mlua::Result<()> {
+                    let position = TilePosition {
+                        x: position.x + tile_offset.0,
+                        y: position.y + tile_offset.1,
+                    };
+                    let orientation = TileOrientation::from_flags(tile.flip_h, tile.flip_v, tile.flip_d);
+                    let tileset = layer_tileset.clone().or_else(|| map_tilesets.get(tile.tileset_index()).cloned().flatten())
+                        .ok_or_else(|| Error::runtime(format!("layer {}: tileset {} isn't registered", layer.name, tile.get_tileset().name)))?;
+                    let cell = server.tile_cell(&tileset, &format!("{}", tile.id()).into(), orientation)
+                        .map_err(|error| Error::runtime(format!("layer {}: {}", layer.name, error)))?;
+                    cells.push((position, cell));
+                    Ok(())
+                };
+                match tiles{
+                    TileLayer::Finite(finite) => {
+                        for x in 0..finite.width() as i32 {
+                            for y in 0..finite.height() as i32 {
+                                if let Some(tile) = finite.get_tile(x, y) {
+                                    place(TilePosition { x, y }, tile)?;
+                                }
+                            }
+                        }
+                    }
+                    TileLayer::Infinite(infinite) => {
+                        for (pos, chunk) in infinite.chunks() {
+                            for x in 0..ChunkData::WIDTH {
+                                for y in 0..ChunkData::HEIGHT {
+                                    if let Some(tile) = chunk.get_tile(x as i32, y as i32) {
+                                        place(TilePosition {
+                                            x: pos.0 * ChunkData::WIDTH as i32 + x as i32,
+                                            y: pos.1 * ChunkData::HEIGHT as i32 + y as i32,
+                                        }, tile)?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                server.set_cells(world.clone(), layer_name, cells)?;
+            }
+            LayerType::Objects(objects) => {
+                for object in objects.objects() {
+                    //objects naming an entity type spawn it, everything else is kept as a map object
+                    let id = match object.properties.get("Class") {
+                        Some(PropertyValue::StringValue(string)) => {
+                            if !server.entity_registry.entities.contains_key(&ImmutableString::from(string.as_str())) {
+                                return Err(Error::runtime(format!("object {} in layer {} uses unknown entity type {}", object.name, layer.name, string)));
+                            }
+                            string.as_str()
+                        }
+                        Some(_) => return Err(Error::runtime(format!("Class property of object {} in layer {} must be a string", object.name, layer.name))),
+                        None if server.entity_registry.entities.contains_key(&ImmutableString::from(object.user_type.as_str())) => object.user_type.as_str(),
+                        None => {
+                            if let Some(map_object) = MapObject::from_tiled(lua, layer.name.as_str(), &object, offset, tile_size)? {
+                                server.worlds.borrow_mut().get_mut(world).unwrap().map_objects.push(map_object);
+                            }
+                            continue;
+                        }
+                    };
+                    Entity::new(lua, id.into(), Position{
+                        x: ((object.x + offset.0) / tile_size.0) as f64,
+                        y: ((object.y + offset.1) / tile_size.1) as f64,
+                        world: world.clone(),
+                    }, EntityVisibility::default(), |data| load_tiled_properties_into_lua_table(lua, data, &object.properties))?;
+                }
+            }
+            LayerType::Image(image_layer) => {
+                let Some(image) = &image_layer.image else {
+                    continue;
+                };
+                let data = std::fs::read(&image.source).map_err(|error| Error::runtime(format!("couldn't read image {} of layer {}: {}", image.source.display(), layer.name, error)))?;
+                let name = image.source.to_string_lossy().to_string();
+                let repeat = |key: &str| matches!(layer.properties.get(key), Some(PropertyValue::BoolValue(true)));
+                let background_image = BackgroundImage {
+                    image: name.clone(),
+                    position: Vec2 { x: (offset.0 / tile_size.0) as f64, y: (offset.1 / tile_size.1) as f64 },
+                    size: Vec2 { x: (image.width as f32 / tile_size.0) as f64, y: (image.height as f32 / tile_size.1) as f64 },
+                    parallax: (layer.parallax_x, layer.parallax_y),
+                    repeat_x: repeat("repeat_x"),
+                    repeat_y: repeat("repeat_y"),
+                    tint: (255, 255, 255, (layer.opacity * 255.) as u8),
+                };
+                server.update_background(world.clone(), |world| {
+                    world.background_images.insert(name, data);
+                    world.background.images.push(background_image);
+                })?;
+            }
+            LayerType::Group(group) => {
+                load_map_layers(lua, server, world, tilesets, map_tilesets, group.layers(), offset, tile_size)?;
+            }
+        }
+    }
+    Ok(())
+}
+/// Reads `file` from the assets folder, the size defaults to the image size at `pixels_per_tile` pixels per tile.
+fn background_image_from_lua(table: &Table) -> mlua::Result<(BackgroundImage, Vec<u8>)> {
+    let file: String = table.get("file")?;
+    let path = format!("assets/{}.png", file);
+    let data = std::fs::read(&path).map_err(|error| Error::runtime(format!("couldn't read {}: {}", path, error)))?;
+    let (width, height) = png_size(&data).ok_or_else(|| Error::runtime(format!("{} isn't a png image", path)))?;
+    let pixels_per_tile = table.get::<_, Option<f64>>("pixels_per_tile")?.unwrap_or(16.);
+    let parallax = table.get::<_, Option<f32>>("parallax")?.unwrap_or(1.);
+    let tint = match table.get::<_, Option<Table>>("tint")? {
+        Some(tint) => (tint.get("r")?, tint.get("g")?, tint.get("b")?, tint.get::<_, Option<u8>>("a")?.unwrap_or(255)),
+        None => (255, 255, 255, 255),
+    };
+    Ok((BackgroundImage {
+        image: file,
+        position: Vec2 { x: table.get::<_, Option<f64>>("x")?.unwrap_or(0.), y: table.get::<_, Option<f64>>("y")?.unwrap_or(0.) },
+        size: Vec2 {
+            x: table.get::<_, Option<f64>>("width")?.unwrap_or(width as f64 / pixels_per_tile),
+            y: table.get::<_, Option<f64>>("height")?.unwrap_or(height as f64 / pixels_per_tile),
+        },
+        parallax: (table.get::<_, Option<f32>>("parallax_x")?.unwrap_or(parallax), table.get::<_, Option<f32>>("parallax_y")?.unwrap_or(parallax)),
+        repeat_x: table.get::<_, Option<bool>>("repeat_x")?.unwrap_or(false),
+        repeat_y: table.get::<_, Option<bool>>("repeat_y")?.unwrap_or(false),
+        tint,
+    }, data))
+}
+pub enum MapObjectShape {
+    Rect,
+    Ellipse,
+    Point,
+    Polygon,
+    Polyline,
+}
+/// Object imported from a Tiled object layer, with coordinates converted to tiles.
+pub struct MapObject {
+    id: u32,
+    layer: String,
+    name: String,
+    object_type: String,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    rotation: f64,
+    shape: MapObjectShape,
+    points: Vec<(f64, f64)>,
+    properties: OwnedTable,
+}
+impl MapObject {
+    pub fn from_tiled(lua: &Lua, layer: &str, object: &ObjectData, offset: (f32, f32), tile_size: (f32, f32)) -> mlua::Result<Option<Self>> {
+        let (tile_width, tile_height) = (tile_size.0 as f64, tile_size.1 as f64);
+        let x = (object.x + offset.0) as f64 / tile_width;
+        let y = (object.y + offset.1) as f64 / tile_height;
+        let (sin, cos) = (object.rotation as f64).to_radians().sin_cos();
+        //tiled rotates objects clockwise around their origin
+        let to_world = |points: &Vec<(f32, f32)>| points.iter().map(|(point_x, point_y)| {
+            let (point_x, point_y) = (*point_x as f64, *point_y as f64);
+            (x + (point_x * cos - point_y * sin) / tile_width, y + (point_x * sin + point_y * cos) / tile_height)
+        }).collect::<Vec<_>>();
+        let (shape, width, height, points) = match &object.shape {
+            ObjectShape::Rect { width, height } => (MapObjectShape::Rect, *width, *height, Vec::new()),
+            ObjectShape::Ellipse { width, height } => (MapObjectShape::Ellipse, *width, *height, Vec::new()),
+            ObjectShape::Point(_, _) => (MapObjectShape::Point, 0., 0., Vec::new()),
+            ObjectShape::Polygon { points } => (MapObjectShape::Polygon, 0., 0., to_world(points)),
+            ObjectShape::Polyline { points } => (MapObjectShape::Polyline, 0., 0., to_world(points)),
+            _ => return Ok(None),
+        };
+        let properties = lua.create_table()?;
+        load_tiled_properties_into_lua_table(lua, &properties, &object.properties)?;
+        Ok(Some(MapObject {
+            id: object.id(),
+            layer: layer.to_string(),
+            name: object.name.clone(),
+            object_type: object.user_type.clone(),
+            x,
+            y,
+            width: width as f64 / tile_width,
+            height: height as f64 / tile_height,
+            rotation: object.rotation as f64,
+            shape,
+            points,
+            properties: properties.into_owned(),
+        }))
+    }
+    pub fn matches(&self, filter: &Table) -> mlua::Result<bool> {
+        for (key, value) in [("layer", &self.layer), ("name", &self.name), ("type", &self.object_type)] {
+            if filter.get::<_, Option<String>>(key)?.is_some_and(|expected| expected != *value) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    pub fn to_lua<'lua>(&self, lua: &'lua Lua, world: &ImmutableString) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("layer", self.layer.as_str())?;
+        table.set("name", self.name.as_str())?;
+        table.set("type", self.object_type.as_str())?;
+        table.set("shape", match self.shape {
+            MapObjectShape::Rect => "rect",
+            MapObjectShape::Ellipse => "ellipse",
+            MapObjectShape::Point => "point",
+            MapObjectShape::Polygon => "polygon",
+            MapObjectShape::Polyline => "polyline",
+        })?;
+        table.set("x", self.x)?;
+        table.set("y", self.y)?;
+        table.set("width", self.width)?;
+        table.set("height", self.height)?;
+        table.set("rotation", self.rotation)?;
+        table.set("position", Position { x: self.x, y: self.y, world: world.clone() })?;
+        let points = lua.create_table()?;
+        for (x, y) in &self.points {
+            points.push(Position { x: *x, y: *y, world: world.clone() })?;
+        }
+        table.set("points", points)?;
+        table.set("properties", self.properties.clone())?;
+        Ok(table)
+    }
+}
+pub fn load_tiled_properties_into_lua_table(lua: &Lua, table: &Table, properties: &Properties) -> mlua::Result<()> {
     for (name, property) in properties{
         match property{
             PropertyValue::BoolValue(value) => {
-                table.set(name.as_str(), *value).unwrap();
+                table.set(name.as_str(), *value)?;
             }
             PropertyValue::FloatValue(value) => {
-                table.set(name.as_str(), *value).unwrap();
+                table.set(name.as_str(), *value)?;
             }
             PropertyValue::IntValue(value) => {
-                table.set(name.as_str(), *value).unwrap();
+                table.set(name.as_str(), *value)?;
+            }
+            PropertyValue::StringValue(value) | PropertyValue::FileValue(value) => {
+                table.set(name.as_str(), value.as_str())?;
+            }
+            PropertyValue::ObjectValue(value) => {
+                table.set(name.as_str(), *value)?;
             }
-            PropertyValue::StringValue(value) => {
-                table.set(name.as_str(), value.as_str()).unwrap();
+            PropertyValue::ColorValue(color) => {
+                let color_table = lua.create_table()?;
+                color_table.set("r", color.red)?;
+                color_table.set("g", color.green)?;
+                color_table.set("b", color.blue)?;
+                color_table.set("a", color.alpha)?;
+                table.set(name.as_str(), color_table)?;
             }
             PropertyValue::ClassValue { property_type, properties } => {
-                let class = lua.create_table().unwrap();
-                class.set("type", property_type.as_str()).unwrap();
-                load_tiled_properties_into_lua_table(lua, &class, properties);
-                table.set(name.as_str(), class).unwrap();
+                let class = lua.create_table()?;
+                class.set("type", property_type.as_str())?;
+                load_tiled_properties_into_lua_table(lua, &class, properties)?;
+                table.set(name.as_str(), class)?;
             }
-            _ => unimplemented!()
+        }
+    }
+    Ok(())
+}
+pub fn load_tiled_collision_shape<'lua>(lua: &'lua Lua, object: &ObjectData, tile_width: f64, tile_height: f64) -> mlua::Result<Option<Table<'lua>>> {
+    let shape = lua.create_table()?;
+    let (x, y) = (object.x as f64, object.y as f64);
+    match &object.shape {
+        ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => {
+            let one_way = object.user_type == "platform" || matches!(object.properties.get("one_way"), Some(PropertyValue::BoolValue(true)));
+            shape.set("type", if one_way { "platform" } else { "box" })?;
+            shape.set("x", x / tile_width)?;
+            shape.set("y", y / tile_height)?;
+            shape.set("w", *width as f64 / tile_width)?;
+            shape.set("h", *height as f64 / tile_height)?;
+        }
+        ObjectShape::Polygon { points } => {
+            let points_table = lua.create_table()?;
+            for (point_x, point_y) in points {
+                let point = lua.create_table()?;
+                point.set("x", (x + *point_x as f64) / tile_width)?;
+                point.set("y", (y + *point_y as f64) / tile_height)?;
+                points_table.push(point)?;
+            }
+            shape.set("type", "polygon")?;
+            shape.set("points", points_table)?;
+        }
+        _ => return Ok(None),
+    }
+    Ok(Some(shape))
+}
+/// Accepts nil, raw flag bits, a rotation preset such as `"rotate_90"` or a table
+/// with `rotation` (degrees clockwise), `flip_x`, `flip_y` or the raw `flip_h`, `flip_v`, `flip_d` flags.
+pub fn tile_orientation_from_lua(value: Value) -> mlua::Result<TileOrientation> {
+    Ok(match value {
+        Value::Nil => TileOrientation::default(),
+        Value::Integer(flags) if (0..8).contains(&flags) => TileOrientation(flags as u8),
+        Value::String(preset) => match preset.to_str()? {
+            "none" => TileOrientation::default(),
+            "rotate_90" => TileOrientation::from_rotation(1, false, false),
+            "rotate_180" => TileOrientation::from_rotation(2, false, false),
+            "rotate_270" => TileOrientation::from_rotation(3, false, false),
+            "flip_x" => TileOrientation::from_rotation(0, true, false),
+            "flip_y" => TileOrientation::from_rotation(0, false, true),
+            preset => return Err(Error::runtime(format!("unknown tile orientation {}", preset))),
+        },
+        Value::Table(table) => {
+            let rotation = table.get::<_, Option<i32>>("rotation")?.unwrap_or(0);
+            if rotation % 90 != 0 {
+                return Err(Error::runtime("tile rotation must be a multiple of 90"));
+            }
+            let orientation = TileOrientation::from_rotation((rotation / 90).rem_euclid(4) as u8, table.get::<_, Option<bool>>("flip_x")?.unwrap_or(false), table.get::<_, Option<bool>>("flip_y")?.unwrap_or(false));
+            let flags = TileOrientation::from_flags(
+                table.get::<_, Option<bool>>("flip_h")?.unwrap_or(false),
+                table.get::<_, Option<bool>>("flip_v")?.unwrap_or(false),
+                table.get::<_, Option<bool>>("flip_d")?.unwrap_or(false),
+            );
+            TileOrientation(orientation.0 ^ flags.0)
+        }
+        _ => return Err(Error::runtime("tile orientation must be nil, flags, a preset name or table")),
+    })
+}
+pub fn tile_orientation_to_lua(lua: &Lua, orientation: TileOrientation) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set("flip_h", orientation.flip_horizontal())?;
+    table.set("flip_v", orientation.flip_vertical())?;
+    table.set("flip_d", orientation.flip_diagonal())?;
+    Ok(table)
+}
+/// Splits a `tileset:tile` reference.
+pub fn parse_tile_reference(reference: &str) -> mlua::Result<(ImmutableString, ImmutableString)> {
+    let (tileset, tile) = reference.split_once(':').ok_or_else(|| Error::runtime(format!("tile {} must be in tileset:tile form", reference)))?;
+    Ok((tileset.into(), tile.into()))
+}
+/// Reads a background table of `set_world_background` or `create_world`, with the image files it uses.
+fn background_from_lua(background: &Table) -> mlua::Result<(WorldBackground, HashMap<String, Vec<u8>>)> {
+    let color = match background.get::<_, Option<Table>>("color")? {
+        Some(color) => (color.get("r")?, color.get("g")?, color.get("b")?, color.get::<_, Option<u8>>("a")?.unwrap_or(255)),
+        None => WorldBackground::default().color,
+    };
+    let mut images = Vec::new();
+    let mut image_data = HashMap::new();
+    for image in background.get::<_, Option<Vec<Table>>>("images")?.unwrap_or_default() {
+        let (image, data) = background_image_from_lua(&image)?;
+        image_data.insert(image.image.clone(), data);
+        images.push(image);
+    }
+    Ok((WorldBackground { color, images }, image_data))
+}
+pub struct LuaTileLayer {
+    layer: ImmutableString,
+}
+impl LuaTileLayer {
+    pub fn new(layer: ImmutableString) -> Self {
+        LuaTileLayer { layer }
+    }
+    fn cell_at(&self, server: &Server, pos: Position) -> TileCell {
+        server.read_cells(&pos.world, &self.layer, [pos.align_to_tile()])[0]
+    }
+    /// Resolves a `tileset:tile` reference into a cell, nil gives an empty cell.
+    fn cell_from_lua(server: &Server, tile: Option<String>, orientation: Value) -> mlua::Result<TileCell> {
+        Ok(match tile {
+            Some(tile) => {
+                let (tileset, id) = parse_tile_reference(&tile)?;
+                server.tile_cell(&tileset, &id, tile_orientation_from_lua(orientation)?)?
+            }
+            None => TileCell::EMPTY,
+        })
+    }
+    /// Most cells a single region operation may touch.
+    const MAX_REGION_CELLS: u32 = 1 << 20;
+    /// Inclusive tile rectangle between two corners in the same world, as its top left tile and size.
+    fn rect(from: &Position, to: &Position) -> mlua::Result<(TilePosition, u32, u32)> {
+        if from.world != to.world {
+            return Err(Error::runtime("both corners of a region must be in the same world"));
+        }
+        let (from, to) = (from.align_to_tile(), to.align_to_tile());
+        let corner = TilePosition { x: from.x.min(to.x), y: from.y.min(to.y) };
+        let (width, height) = (from.x.abs_diff(to.x).saturating_add(1), from.y.abs_diff(to.y).saturating_add(1));
+        if width.checked_mul(height).is_none_or(|cells| cells > LuaTileLayer::MAX_REGION_CELLS) {
+            return Err(Error::runtime(format!("region {}x{} is larger than {} cells", width, height, LuaTileLayer::MAX_REGION_CELLS)));
+        }
+        Ok((corner, width, height))
+    }
+}
+/// Which cells `flood_fill` spreads into.
+enum FillPredicate<'lua> {
+    /// Cells holding the same tile, in any orientation.
+    Tile(TileCell),
+    Function(Function<'lua>),
+}
+impl<'lua> FillPredicate<'lua> {
+    fn matches(&self, server: &Server, position: Position, cell: TileCell) -> mlua::Result<bool> {
+        match self {
+            FillPredicate::Tile(tile) => Ok(cell.tileset == tile.tileset && (cell.is_empty() || cell.id == tile.id)),
+            FillPredicate::Function(function) => function.call((position, server.tile_reference(cell))),
         }
     }
 }
-pub struct LuaTileSet {
-    tileset: ImmutableString,
+/// Copied cells of a tile layer, row by row.
+pub struct TileRegion {
+    width: u32,
+    height: u32,
+    cells: Vec<TileCell>,
 }
-impl UserData for LuaTileSet {
+impl UserData for TileRegion {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("width", |_, region| Ok(region.width));
+        fields.add_field_method_get("height", |_, region| Ok(region.height));
+    }
+}
+impl UserData for LuaTileLayer {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("name", |_, tile_layer| Ok(tile_layer.layer.to_string()));
+    }
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("get_at", |lua, tile_map, (pos, ): (Position,)| {
+        methods.add_method("get_at", |lua, tile_layer, (pos, ): (Position,)| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            let (chunk_position, chunk_offset) = pos.align_to_tile().to_chunk_position();
-            let chunk = server.get_chunk(chunk_position, pos.world);
-            let tile_id = match chunk.tile_layers.get(&tile_map.tileset) {
-                Some(tileset) => {
-                    tileset.0[chunk_offset.index()]
-                }
-                None => {
-                    0
+            let cell = tile_layer.cell_at(&server, pos);
+            Ok(server.tile_type(cell).map(|tile_type| tile_type.data.clone()))
+        });
+        methods.add_method("get_id_at", |lua, tile_layer, (pos, ): (Position,)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let cell = tile_layer.cell_at(&server, pos);
+            Ok(server.tile_reference(cell))
+        });
+        methods.add_method("set_at", |lua, tile_layer, (pos, tile, orientation): (Position, Option<String>, Value)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let cell = LuaTileLayer::cell_from_lua(&server, tile, orientation)?;
+            server.set_cell(pos.align_to_tile(), pos.world.clone(), tile_layer.layer.clone(), cell)
+        });
+        methods.add_method("fill_rect", |lua, tile_layer, (from, to, tile, orientation): (Position, Position, Option<String>, Value)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let cell = LuaTileLayer::cell_from_lua(&server, tile, orientation)?;
+            let (corner, width, height) = LuaTileLayer::rect(&from, &to)?;
+            let cells = (0..height as i32).flat_map(|y| (0..width as i32).map(move |x| (TilePosition { x: corner.x + x, y: corner.y + y }, cell)));
+            server.set_cells(from.world.clone(), tile_layer.layer.clone(), cells)
+        });
+        methods.add_method("set_tiles", |lua, tile_layer, tiles: Vec<Table>| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            //entries are {pos, tile, orientation}, grouped by world
+            let mut worlds: HashMap<ImmutableString, Vec<(TilePosition, TileCell)>> = HashMap::new();
+            for entry in tiles {
+                let pos: Position = entry.get(1)?;
+                let cell = LuaTileLayer::cell_from_lua(&server, entry.get(2)?, entry.get(3)?)?;
+                worlds.entry(pos.world.clone()).or_default().push((pos.align_to_tile(), cell));
+            }
+            for (world, cells) in worlds {
+                server.set_cells(world, tile_layer.layer.clone(), cells)?;
+            }
+            Ok(())
+        });
+        methods.add_method("get_neighbours", |lua, tile_layer, (pos, diagonal): (Position, Option<bool>)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let center = pos.align_to_tile();
+            let positions: Vec<TilePosition> = NEIGHBOURS.iter()
+                .filter(|(x, y)| diagonal.unwrap_or(false) || *x == 0 || *y == 0)
+                .map(|(x, y)| TilePosition { x: center.x + x, y: center.y + y })
+                .collect();
+            let cells = server.read_cells(&pos.world, &tile_layer.layer, positions.iter().cloned());
+            let neighbours = lua.create_table()?;
+            for (position, cell) in positions.into_iter().zip(cells) {
+                let neighbour = lua.create_table()?;
+                neighbour.set("pos", Position::from_tile(position, pos.world.clone()))?;
+                neighbour.set("tile", server.tile_reference(cell))?;
+                neighbours.push(neighbour)?;
+            }
+            Ok(neighbours)
+        });
+        methods.add_method("flood_fill", |lua, tile_layer, (pos, predicate, limit): (Position, Value, Option<usize>)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let start = pos.align_to_tile();
+            let to_position = |position: TilePosition| Position::from_tile(position, pos.world.clone());
+            //without a predicate the fill spreads over the tile at the start
+            let predicate = match predicate {
+                Value::Nil => FillPredicate::Tile(server.read_cells(&pos.world, &tile_layer.layer, [start])[0]),
+                Value::String(tile) => {
+                    let (tileset, id) = parse_tile_reference(tile.to_str()?)?;
+                    FillPredicate::Tile(server.tile_cell(&tileset, &id, TileOrientation::default())?)
                 }
+                Value::Function(function) => FillPredicate::Function(function),
+                _ => return Err(Error::runtime("flood fill predicate must be nil, a tile or a function")),
             };
-            let tileset = server.tile_sets.get(&tile_map.tileset).ok_or(Error::runtime("tileset doesn't exist"))?;
-            Ok(tileset.tiles.get(&tileset.tile_ids[tile_id as usize]).unwrap().data.clone())
+            let limit = limit.unwrap_or(4096);
+            let mut filled = Vec::new();
+            let start_cell = server.read_cells(&pos.world, &tile_layer.layer, [start])[0];
+            if !predicate.matches(&server, to_position(start), start_cell)? {
+                return Ok((filled, true));
+            }
+            let mut visited = HashSet::from([(start.x, start.y)]);
+            let mut queue = VecDeque::from([start]);
+            while let Some(position) = queue.pop_front() {
+                if filled.len() >= limit {
+                    return Ok((filled, false));
+                }
+                filled.push(to_position(position));
+                let neighbours: Vec<TilePosition> = NEIGHBOURS.iter()
+                    .filter(|(x, y)| *x == 0 || *y == 0)
+                    .map(|(x, y)| TilePosition { x: position.x + x, y: position.y + y })
+                    .filter(|neighbour| visited.insert((neighbour.x, neighbour.y)))
+                    .collect();
+                //cells are read before calling the predicate, which may itself access tiles
+                let cells = server.read_cells(&pos.world, &tile_layer.layer, neighbours.iter().cloned());
+                for (neighbour, cell) in neighbours.into_iter().zip(cells) {
+                    if predicate.matches(&server, to_position(neighbour), cell)? {
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+            Ok((filled, true))
         });
-        methods.add_method("set_at", |lua, tile_map, (pos, id): (Position, String)| {
+        methods.add_method("find_tiles", |lua, tile_layer, (from, to, tile): (Position, Position, String)| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            let tile_pos = pos.align_to_tile();
-            server.set_tile(tile_pos, pos.world.clone(), tile_map.tileset.clone(), id.into())
+            let (tileset, id) = parse_tile_reference(&tile)?;
+            let target = server.tile_cell(&tileset, &id, TileOrientation::default())?;
+            let (corner, width, height) = LuaTileLayer::rect(&from, &to)?;
+            let positions: Vec<TilePosition> = (0..height as i32).flat_map(|y| (0..width as i32).map(move |x| TilePosition { x: corner.x + x, y: corner.y + y })).collect();
+            let cells = server.read_cells(&from.world, &tile_layer.layer, positions.iter().cloned());
+            Ok(positions.into_iter().zip(cells)
+                .filter(|(_, cell)| cell.tileset == target.tileset && cell.id == target.id)
+                .map(|(position, _)| Position::from_tile(position, from.world.clone()))
+                .collect::<Vec<_>>())
         });
-        methods.add_method("get_data_at", |lua, tile_map, (pos, ): (Position,)| {
+        methods.add_method("read_region", |lua, tile_layer, (from, to): (Position, Position)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let (corner, width, height) = LuaTileLayer::rect(&from, &to)?;
+            let positions = (0..height as i32).flat_map(|y| (0..width as i32).map(move |x| TilePosition { x: corner.x + x, y: corner.y + y }));
+            let cells = server.read_cells(&from.world, &tile_layer.layer, positions);
+            //rows are indexed [y][x] from the top left corner, empty cells are false
+            let rows = lua.create_table()?;
+            for row in cells.chunks(width as usize) {
+                let row_table = lua.create_table()?;
+                for cell in row {
+                    match server.tile_reference(*cell) {
+                        Some(tile) => row_table.push(tile)?,
+                        None => row_table.push(false)?,
+                    }
+                }
+                rows.push(row_table)?;
+            }
+            Ok(rows)
+        });
+        methods.add_method("copy_region", |lua, tile_layer, (from, to): (Position, Position)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let (corner, width, height) = LuaTileLayer::rect(&from, &to)?;
+            let positions = (0..height as i32).flat_map(|y| (0..width as i32).map(move |x| TilePosition { x: corner.x + x, y: corner.y + y }));
+            let cells = server.read_cells(&from.world, &tile_layer.layer, positions);
+            Ok(TileRegion { width, height, cells })
+        });
+        methods.add_method("paste_region", |lua, tile_layer, (region, pos): (AnyUserData, Position)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let region = region.borrow::<TileRegion>()?;
+            let corner = pos.align_to_tile();
+            let cells = region.cells.iter().enumerate().map(|(index, cell)| (TilePosition {
+                x: corner.x + (index as u32 % region.width) as i32,
+                y: corner.y + (index as u32 / region.width) as i32,
+            }, *cell));
+            server.set_cells(pos.world.clone(), tile_layer.layer.clone(), cells)
+        });
+        methods.add_method("set_terrain", |lua, tile_layer, (pos, terrain): (Position, Option<String>)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let terrain = terrain.map(|terrain| server.find_terrain(&terrain)).transpose()?;
+            server.set_terrain(pos.align_to_tile(), pos.world.clone(), tile_layer.layer.clone(), terrain)
+        });
+        methods.add_method("get_terrain_at", |lua, tile_layer, (pos, ): (Position,)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            Ok(server.terrain_at(pos.align_to_tile(), pos.world.clone(), &tile_layer.layer).map(|(tileset, terrain)| format!("{}:{}", tileset, terrain)))
+        });
+        methods.add_method("schedule_update", |lua, tile_layer, (pos, after): (Position, f64)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            server.schedule_tile_update(pos.align_to_tile(), pos.world.clone(), tile_layer.layer.clone(), after);
+            Ok(())
+        });
+        methods.add_method("get_orientation_at", |lua, tile_layer, (pos, ): (Position,)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let cell = tile_layer.cell_at(&server, pos);
+            tile_orientation_to_lua(lua, cell.orientation)
+        });
+        methods.add_method("get_data_at", |lua, tile_layer, (pos, ): (Position,)| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
             let (chunk_position, chunk_offset) = pos.align_to_tile().to_chunk_position();
-            let mut chunk = server.get_chunk(chunk_position, pos.world);
-            let tile_layer = chunk.tile_layers.entry(tile_map.tileset.clone()).or_insert_with(|| ChunkTileLayer::new());
-            let tileset = server.tile_sets.get(&tile_map.tileset).ok_or(Error::runtime("tileset not found"))?;
-            let tile_table = tileset.tiles.get(tileset.tile_ids.get(tile_layer.0[chunk_offset.x as usize + (chunk_offset.y as usize * CHUNK_SIZE as usize)] as usize).unwrap()).unwrap().data.clone();
-            Ok(tile_layer.1.entry(chunk_offset).or_insert_with(move || {
-                let table = lua.create_table().unwrap().into_owned();
-                table.to_ref().set_metatable(Some({
-                    let meta = lua.create_table().unwrap();
-                    meta.set("__index", tile_table).unwrap();
-                    meta
-                }));
-                table
-            }).clone())
+            //only cells holding a tile have data, so reading doesn't create chunks
+            let Some(tile_type) = server.tile_type(tile_layer.cell_at(&server, pos.clone())) else {
+                return Ok(None);
+            };
+            let mut chunk = server.get_chunk(chunk_position, pos.world.clone())?;
+            let cells = chunk.tile_layers.get_mut(&tile_layer.layer).unwrap();
+            if !cells.1.contains_key(&chunk_offset) {
+                let tile_data = TileData::new(lua, tile_type.data.clone(), pos.world.clone(), tile_layer.layer.clone(), pos.align_to_tile(), lua.create_table()?)?;
+                cells.1.insert(chunk_offset, tile_data);
+            }
+            Ok(Some(cells.1.get(&chunk_offset).unwrap().proxy.clone()))
         });
     }
 }
+/// Data of one placed tile. Scripts get `proxy`, which reads `values` before the tile type's data
+/// and reports writes to `Server::tile_data_changed`.
+pub struct TileData {
+    pub values: OwnedTable,
+    pub proxy: OwnedTable,
+    /// Set once the tile is replaced, writes through a proxy kept by a script are no longer reported.
+    invalid: Rc<Cell<bool>>,
+}
+impl TileData {
+    const METATABLE: &'static str = r#"
+        local values, type_data, changed = ...
+        return {
+            __index = function(_, key)
+                local value = values[key]
+                if value == nil then
+                    return type_data[key]
+                end
+                return value
+            end,
+            __newindex = function(_, key, value)
+                local old = values[key]
+                if old == nil then
+                    old = type_data[key]
+                end
+                if old == value then
+                    return
+                end
+                values[key] = value
+                changed(key, old, value)
+            end,
+            __pairs = function()
+                return next, values, nil
+            end,
+        }
+    "#;
+    pub fn new(lua: &Lua, type_data: OwnedTable, world: ImmutableString, layer: ImmutableString, position: TilePosition, values: Table) -> mlua::Result<Self> {
+        let metatable_factory = match lua.named_registry_value::<Option<Function>>("tile_data_metatable")? {
+            Some(factory) => factory,
+            None => {
+                let factory = lua.load(TileData::METATABLE).set_name("tile_data").into_function()?;
+                lua.set_named_registry_value("tile_data_metatable", factory.clone())?;
+                factory
+            }
+        };
+        let invalid = Rc::new(Cell::new(false));
+        let changed_invalid = invalid.clone();
+        let changed = lua.create_function(move |lua, (key, old, new): (Value, Value, Value)| {
+            if changed_invalid.get() {
+                return Ok(());
+            }
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            server.tile_data_changed(world.clone(), layer.clone(), position, key, old, new)
+        })?;
+        let proxy = lua.create_table()?;
+        proxy.set_metatable(Some(metatable_factory.call::<_, Table>((values.clone(), type_data, changed))?));
+        Ok(TileData {
+            values: values.into_owned(),
+            proxy: proxy.into_owned(),
+            invalid,
+        })
+    }
+    pub fn invalidate(&self) {
+        self.invalid.set(true);
+    }
+}
+/// Deepest nesting of tables a tile data value may have.
+const MAX_TILE_DATA_DEPTH: usize = 32;
+/// Converts a Lua value for replication or saving, nil gives `None`.
+pub fn tile_data_value_from_lua(value: &Value) -> mlua::Result<Option<TileDataValue>> {
+    tile_data_value_from_lua_nested(value, &mut Vec::new())
+}
+/// `parents` holds the tables currently being converted, seeing one of them again means the table contains itself.
+fn tile_data_value_from_lua_nested(value: &Value, parents: &mut Vec<*const std::ffi::c_void>) -> mlua::Result<Option<TileDataValue>> {
+    Ok(Some(match value {
+        Value::Nil => return Ok(None),
+        Value::Boolean(value) => TileDataValue::Boolean(*value),
+        Value::Integer(value) => TileDataValue::Integer(*value),
+        Value::Number(value) => TileDataValue::Number(*value),
+        Value::String(value) => TileDataValue::String(value.to_str()?.to_string()),
+        Value::Table(table) => {
+            if parents.contains(&table.to_pointer()) {
+                return Err(Error::runtime("tile data can't hold a table that contains itself"));
+            }
+            if parents.len() >= MAX_TILE_DATA_DEPTH {
+                return Err(Error::runtime(format!("tile data can't nest tables deeper than {} levels", MAX_TILE_DATA_DEPTH)));
+            }
+            parents.push(table.to_pointer());
+            let pairs = table.clone().pairs::<Value, Value>().map(|pair| {
+                let (key, value) = pair?;
+                let key = tile_data_value_from_lua_nested(&key, parents)?.unwrap();
+                let value = tile_data_value_from_lua_nested(&value, parents)?.unwrap();
+                Ok((key, value))
+            }).collect::<mlua::Result<Vec<_>>>();
+            parents.pop();
+            TileDataValue::Table(pairs?)
+        }
+        _ => return Err(Error::runtime(format!("tile data can't hold a {}", value.type_name()))),
+    }))
+}
+pub fn tile_data_value_to_lua<'lua>(lua: &'lua Lua, value: &TileDataValue) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        TileDataValue::Boolean(value) => Value::Boolean(*value),
+        TileDataValue::Integer(value) => Value::Integer(*value),
+        TileDataValue::Number(value) => Value::Number(*value),
+        TileDataValue::String(value) => Value::String(lua.create_string(value)?),
+        TileDataValue::Table(pairs) => {
+            let table = lua.create_table()?;
+            for (key, value) in pairs {
+                table.raw_set(tile_data_value_to_lua(lua, key)?, tile_data_value_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
 #[derive(Clone, FromLua)]
 pub struct Position {
     pub x: f64,
@@ -302,6 +1123,13 @@ impl UserData for Position {
     }
 }
 impl Position {
+    pub fn from_tile(position: TilePosition, world: ImmutableString) -> Self {
+        Position {
+            x: position.x as f64,
+            y: position.y as f64,
+            world,
+        }
+    }
     pub fn align_to_tile(&self) -> TilePosition {
         TilePosition {
             x: self.x as i32,
@@ -313,11 +1141,26 @@ impl Position {
 pub struct Collider {
     pub(crate) aabb: AABB,
     pub(crate) mask: u32,
+    pub(crate) collides_with: Option<u32>,
+}
+pub struct CollisionMask(pub u32);
+impl<'lua> FromLua<'lua> for CollisionMask {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+        if let Some(init_env) = lua.app_data_ref::<InitEnvironment>() {
+            let mask = init_env.collision_layers.borrow().resolve(value);
+            return mask.map(CollisionMask);
+        }
+        let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+        server.collision_layers.resolve(value).map(CollisionMask)
+    }
 }
 #[derive(Clone)]
 pub struct EntityAnimation {
     begin_time: u32,
     animation: ImmutableString,
+    processed_step: Option<usize>,
+    ended: bool,
+    next: Option<ImmutableString>,
 }
 impl EntityAnimation {
     pub fn running_for(&self, server: &Server) -> f64 {
@@ -328,38 +1171,85 @@ pub struct Entity {
     pub type_id: ImmutableString,
     pub uuid: Uuid,
     pub position: RefCell<Position>,
+    tags: RefCell<HashSet<ImmutableString>>,
     removed: AtomicBool,
     animation: RefCell<EntityAnimation>,
+    parent: RefCell<Option<EntityParent>>,
+    children: RefCell<HashMap<Uuid, OwnedAnyUserData>>,
+    visuals: RefCell<EntityVisuals>,
+    visibility: RefCell<EntityVisibility>,
+    /// Clients that were sent this entity and weren't told to remove it since.
+    shown_to: RefCell<HashSet<Uuid>>,
+}
+#[derive(Default)]
+pub struct EntityVisibility {
+    allowed: Option<HashSet<Uuid>>,
+    hidden: HashSet<Uuid>,
+    owner: Option<Uuid>,
+    owner_only: bool,
+    predicate: Option<OwnedFunction>,
+}
+pub struct EntityParent {
+    uuid: Uuid,
+    offset: Vec2,
+    remove_with_parent: bool,
 }
 impl Entity {
-    pub fn new(lua: &Lua, id: ImmutableString, position: Position) -> mlua::Result<OwnedAnyUserData> {
+    /// Spawns an entity, `init_data` fills its data table before anything else sees the entity, `on_spawn` included.
+    pub fn new(lua: &Lua, id: ImmutableString, position: Position, visibility: EntityVisibility, init_data: impl FnOnce(&Table) -> mlua::Result<()>) -> mlua::Result<OwnedAnyUserData> {
         let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-        let mut chunk = server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone());
+        let entity_type = server.entity_registry.entities.get(&id).ok_or(Error::runtime("entity type doesn't exist"))?;
+        if !server.world_exists(&position.world) {
+            return Err(Error::runtime(format!("world {} doesn't exist", position.world)));
+        }
         let table = lua.create_table().unwrap().into_owned();
-        table.to_ref().set_metatable(Some(server.entity_registry.entities.get(&id).unwrap().data_metatable.to_ref()));
+        table.to_ref().set_metatable(Some(entity_type.data_metatable.to_ref()));
+        init_data(&table.to_ref())?;
         let uuid = Uuid::new_v4();
+        let tags: HashSet<ImmutableString> = entity_type.tags.iter().cloned().collect();
         let user_data = lua.create_userdata(Entity {
             type_id: id,
             uuid,
             position: RefCell::new(position.clone()),
+            tags: RefCell::new(tags.clone()),
             removed: AtomicBool::new(false),
             animation: RefCell::new(EntityAnimation {
                 animation: "default".into(),
                 begin_time: server.ticks_passed.get(),
+                processed_step: None,
+                ended: false,
+                next: None,
             }),
+            parent: RefCell::new(None),
+            children: RefCell::new(HashMap::new()),
+            visuals: RefCell::new(EntityVisuals::default()),
+            visibility: RefCell::new(visibility),
+            shown_to: RefCell::new(HashSet::new()),
         }).unwrap().into_owned();
         user_data.to_ref().set_nth_user_value(2, table).unwrap();
         server.entities.borrow_mut().insert(uuid, user_data.clone());
-        chunk.entities.insert(uuid, user_data.clone());
-        for viewer in chunk.viewers.borrow().values(){
-            let _ = viewer.borrow::<Client>().unwrap().connection.sender.send(MessageS2C::AddEntity(user_data.borrow::<Entity>().unwrap().create_add_message(&server)));
+        for tag in tags {
+            server.tagged_entities.borrow_mut().entry(tag).or_insert_with(HashMap::new).insert(uuid, user_data.clone());
         }
+        server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone())?.entities.insert(uuid, user_data.clone());
+        user_data.borrow::<Entity>().unwrap().refresh_visibility(&server)?;
+        entity_type.call_callback("on_spawn", user_data.clone())?;
         Ok(user_data)
     }
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::SeqCst)
+    }
+    pub fn bounds(&self, server: &Server) -> AABB {
+        let position = self.position.borrow();
+        let size = server.entity_registry.entities.get(&self.type_id).unwrap().size;
+        AABB { x: position.x, y: position.y, w: size.0, h: size.1 }
+    }
     pub fn create_add_message(&self, server: &Server) -> EntityAddMessage {
         let position = self.position.borrow();
         let animation = self.animation.borrow();
         EntityAddMessage {
+            parent: self.parent.borrow().as_ref().map(|parent| (parent.uuid, parent.offset)),
+            visuals: *self.visuals.borrow(),
             position: Vec2 { x: position.x, y: position.y },
             entity_type: self.type_id.to_string(),
             uuid: self.uuid,
@@ -369,12 +1259,289 @@ impl Entity {
             },
         }
     }
-    fn sync_animations(&self, server: &Server) {
+    fn chunk_viewers(&self, server: &Server) -> Vec<Uuid> {
         let position = self.position.borrow();
+        let viewers = match server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone()) {
+            Ok(chunk) => chunk.viewers.borrow().keys().cloned().collect(),
+            Err(_) => Vec::new(),
+        };
+        viewers
+    }
+    fn send_to_viewers(&self, server: &Server, message: impl Fn() -> MessageS2C) {
+        for viewer in self.shown_to.borrow().iter() {
+            server.try_send_message_to(*viewer, message());
+        }
+    }
+    pub fn can_be_seen_by(&self, server: &Server, client: Uuid) -> mlua::Result<bool> {
+        let visibility = self.visibility.borrow();
+        if visibility.owner_only && visibility.owner != Some(client) {
+            return Ok(false);
+        }
+        if visibility.hidden.contains(&client) {
+            return Ok(false);
+        }
+        if visibility.allowed.as_ref().is_some_and(|allowed| !allowed.contains(&client)) && visibility.owner != Some(client) {
+            return Ok(false);
+        }
+        if let Some(predicate) = &visibility.predicate {
+            let entity_obj = server.entities.borrow().get(&self.uuid).cloned();
+            let client_obj = server.clients.borrow().get(&client).cloned();
+            if let (Some(entity_obj), Some(client_obj)) = (entity_obj, client_obj) {
+                return predicate.call::<_, bool>((entity_obj, client_obj));
+            }
+        }
+        Ok(true)
+    }
+    /// Sends the entity to viewers of its chunk that can now see it and removes it from those that no longer can.
+    fn refresh_visibility(&self, server: &Server) -> mlua::Result<()> {
+        for viewer in self.chunk_viewers(server) {
+            let visible = self.can_be_seen_by(server, viewer)?;
+            if visible == self.shown_to.borrow().contains(&viewer) {
+                continue;
+            }
+            if visible {
+                server.try_send_message_to(viewer, MessageS2C::AddEntity(self.create_add_message(server)));
+                self.shown_to.borrow_mut().insert(viewer);
+            } else {
+                server.try_send_message_to(viewer, MessageS2C::RemoveEntity(self.uuid));
+                self.shown_to.borrow_mut().remove(&viewer);
+            }
+        }
+        Ok(())
+    }
+    pub fn play(&self, server: &Server, animation_id: ImmutableString, next: Option<ImmutableString>) -> mlua::Result<()> {
+        if self.is_removed() {
+            return Err(Error::runtime("can't animate removed entity"));
+        }
+        let entity_type = server.entity_registry.entities.get(&self.type_id).unwrap();
+        for id in std::iter::once(&animation_id).chain(next.as_ref()) {
+            if !entity_type.animations.contains_key(id) {
+                return Err(Error::runtime(format!("animation {} doesn't exist", id.to_string())));
+            }
+        }
+        *self.animation.borrow_mut() = EntityAnimation {
+            begin_time: server.ticks_passed.get(),
+            animation: animation_id,
+            processed_step: None,
+            ended: false,
+            next,
+        };
+        self.sync_animations(server);
+        Ok(())
+    }
+    /// Fires animation events for every step reached since the last tick and handles the end of the animation.
+    pub fn tick_animation(server: &Server, obj: &OwnedAnyUserData) -> mlua::Result<()> {
+        let entity = obj.borrow::<Entity>()?;
+        if entity.is_removed() {
+            return Ok(());
+        }
+        let entity_type = server.entity_registry.entities.get(&entity.type_id).unwrap();
+        let (animation_id, events, ended, next) = {
+            let mut animation = entity.animation.borrow_mut();
+            let Some(data) = entity_type.animations.get(&animation.animation) else {
+                return Ok(());
+            };
+            let time = animation.running_for(server);
+            let step = data.step_at(time);
+            let mut events = Vec::new();
+            if let Some(frame_events) = entity_type.animation_events.get(&animation.animation) {
+                let first = match animation.processed_step {
+                    Some(processed) => processed + 1,
+                    None => 0,
+                }.max((step + 1).saturating_sub(data.step_count()));
+                for step in first..=step {
+                    if let Some(step_events) = frame_events.get(&data.frame_of_step(step)) {
+                        events.extend(step_events.iter().cloned());
+                    }
+                }
+            }
+            animation.processed_step = Some(animation.processed_step.map_or(step, |processed| processed.max(step)));
+            let ended = !animation.ended && (!data.looped || animation.next.is_some()) && time >= data.cycle_duration();
+            if ended {
+                animation.ended = true;
+            }
+            (animation.animation.clone(), events, ended, if ended { animation.next.take() } else { None })
+        };
+        drop(entity);
+        for event in events {
+            entity_type.call_callback("on_animation_event", (obj.clone(), event.to_string(), animation_id.to_string()))?;
+        }
+        if ended {
+            entity_type.call_callback("on_animation_end", (obj.clone(), animation_id.to_string()))?;
+            if let Some(next) = next {
+                let entity = obj.borrow::<Entity>()?;
+                if entity.animation.borrow().animation == animation_id && entity.animation.borrow().ended {
+                    entity.play(server, next, None)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn sync_animations(&self, server: &Server) {
         let animation = self.animation.borrow();
-        for viewer in server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone()).viewers.borrow().values() {
-            let _ = viewer.borrow::<Client>().unwrap().connection.sender.send(MessageS2C::UpdateEntityAnimation(self.uuid, RunningAnimation { id: animation.animation.to_string(), time: animation.running_for(server) as f32 }));
+        self.send_to_viewers(server, || MessageS2C::UpdateEntityAnimation(self.uuid, RunningAnimation { id: animation.animation.to_string(), time: animation.running_for(server) as f32 }));
+    }
+    fn update_visuals(&self, server: &Server, update: impl FnOnce(&mut EntityVisuals)) {
+        update(&mut *self.visuals.borrow_mut());
+        let visuals = *self.visuals.borrow();
+        self.send_to_viewers(server, || MessageS2C::UpdateEntityVisuals(self.uuid, visuals));
+    }
+    fn sync_parent(&self, server: &Server) {
+        let parent = self.parent.borrow().as_ref().map(|parent| (parent.uuid, parent.offset));
+        self.send_to_viewers(server, || MessageS2C::SetEntityParent(self.uuid, parent));
+        if parent.is_none() {
+            let position = self.position.borrow().clone();
+            self.send_to_viewers(server, || MessageS2C::UpdateEntityPosition(self.uuid, Vec2 { x: position.x, y: position.y }));
+        }
+    }
+    /// Moves the entity and its children. Children send their own position too,
+    /// it is what clients fall back to when they can't see the parent.
+    /// The move always completes, a failing visibility predicate is reported afterwards.
+    fn set_position(&self, server: &Server, entity_obj: &OwnedAnyUserData, position: Position, chunk_changes: &mut Vec<(OwnedAnyUserData, Position, Position)>) -> mlua::Result<()> {
+        if !server.world_exists(&position.world) {
+            return Err(Error::runtime(format!("world {} doesn't exist", position.world)));
+        }
+        let mut error = None;
+        let old_position = self.position.borrow().clone();
+        let old_chunk_position = old_position.align_to_tile().to_chunk_position().0;
+        let new_chunk_position = position.align_to_tile().to_chunk_position().0;
+        if old_position.world != position.world || old_chunk_position != new_chunk_position {
+            let old_viewers: HashSet<Uuid> = {
+                let mut old_chunk = server.get_chunk(old_chunk_position, old_position.world.clone())?;
+                old_chunk.entities.remove(&self.uuid);
+                let v = old_chunk.viewers.borrow().keys().cloned().collect();
+                v
+            };
+            let new_viewers: HashSet<Uuid> = {
+                let mut new_chunk = server.get_chunk(new_chunk_position, position.world.clone())?;
+                new_chunk.entities.insert(self.uuid, entity_obj.clone());
+                let v = new_chunk.viewers.borrow().keys().cloned().collect();
+                v
+            };
+            *self.position.borrow_mut() = position.clone();
+            for new_viewer in new_viewers.difference(&old_viewers) {
+                match self.can_be_seen_by(server, *new_viewer) {
+                    Ok(true) => {
+                        server.try_send_message_to(*new_viewer, MessageS2C::AddEntity(self.create_add_message(server)));
+                        self.shown_to.borrow_mut().insert(*new_viewer);
+                    }
+                    Ok(false) => {}
+                    Err(predicate_error) => {
+                        error.get_or_insert(predicate_error);
+                    }
+                }
+            }
+            for viewer in new_viewers.intersection(&old_viewers) {
+                if self.shown_to.borrow().contains(viewer) {
+                    server.try_send_message_to(*viewer, MessageS2C::UpdateEntityPosition(self.uuid, Vec2 { x: position.x, y: position.y }));
+                }
+            }
+            for old_viewer in old_viewers.difference(&new_viewers) {
+                if self.shown_to.borrow_mut().remove(old_viewer) {
+                    server.try_send_message_to(*old_viewer, MessageS2C::RemoveEntity(self.uuid));
+                }
+            }
+            chunk_changes.push((entity_obj.clone(), old_position, position.clone()));
+        } else {
+            *self.position.borrow_mut() = position.clone();
+            self.send_to_viewers(server, || MessageS2C::UpdateEntityPosition(self.uuid, Vec2 { x: position.x, y: position.y }));
+        }
+        for child_obj in self.children.borrow().values() {
+            let child = child_obj.borrow::<Entity>().unwrap();
+            let offset = child.parent.borrow().as_ref().unwrap().offset;
+            if let Err(child_error) = child.set_position(server, child_obj, Position {
+                x: position.x + offset.x,
+                y: position.y + offset.y,
+                world: position.world.clone(),
+            }, chunk_changes) {
+                error.get_or_insert(child_error);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+    fn call_chunk_change_callbacks(server: &Server, chunk_changes: Vec<(OwnedAnyUserData, Position, Position)>) -> mlua::Result<()> {
+        for (entity_obj, old_position, new_position) in chunk_changes {
+            let type_id = entity_obj.borrow::<Entity>()?.type_id.clone();
+            server.entity_registry.entities.get(&type_id).unwrap().call_callback("on_chunk_change", (entity_obj, old_position, new_position))?;
+        }
+        Ok(())
+    }
+    fn unlink_from_parent(&self, server: &Server) -> Option<EntityParent> {
+        let parent = self.parent.borrow_mut().take()?;
+        if let Some(parent_obj) = server.entities.borrow().get(&parent.uuid) {
+            parent_obj.borrow::<Entity>().unwrap().children.borrow_mut().remove(&self.uuid);
         }
+        Some(parent)
+    }
+    pub fn remove(server: &Server, entity_obj: &OwnedAnyUserData) -> mlua::Result<()> {
+        let entity = entity_obj.borrow::<Entity>()?;
+        if entity.removed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        server.entities.borrow_mut().remove(&entity.uuid);
+        for tag in entity.tags.borrow().iter() {
+            if let Some(tagged) = server.tagged_entities.borrow_mut().get_mut(tag) {
+                tagged.remove(&entity.uuid);
+            }
+        }
+        let position = entity.position.borrow().clone();
+        {
+            if let Ok(mut chunk) = server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone()) {
+                chunk.entities.remove(&entity.uuid);
+            }
+        }
+        for viewer in std::mem::take(&mut *entity.shown_to.borrow_mut()) {
+            server.try_send_message_to(viewer, MessageS2C::RemoveEntity(entity.uuid));
+        }
+        //the removal always completes, errors are reported once everything is torn down
+        let mut error = None;
+        for client_obj in server.clients.borrow().values() {
+            match client_obj.borrow_mut::<Client>() {
+                Ok(mut client) => {
+                    if let Err(camera_error) = client.release_camera_of(server, client_obj.clone(), entity.uuid, &position) {
+                        error.get_or_insert(camera_error);
+                    }
+                }
+                //a client that is busy, like one whose method removed the entity, switches on the next task run
+                Err(_) => {
+                    let (client_obj, uuid, position) = (client_obj.clone(), entity.uuid, position.clone());
+                    server.schedule_task(move |server| {
+                        let Ok(mut client) = client_obj.borrow_mut::<Client>() else {
+                            return Some(0.);
+                        };
+                        if let Err(error) = client.release_camera_of(server, client_obj.clone(), uuid, &position) {
+                            eprintln!("error releasing camera of removed entity {}: {}", uuid, error);
+                        }
+                        None
+                    }, 0.);
+                }
+            }
+        }
+        entity.unlink_from_parent(server);
+        let children = std::mem::take(&mut *entity.children.borrow_mut());
+        let entity_type = server.entity_registry.entities.get(&entity.type_id).unwrap();
+        drop(entity);
+        for child_obj in children.values() {
+            let child = match child_obj.borrow::<Entity>() {
+                Ok(child) => child,
+                Err(child_error) => {
+                    error.get_or_insert(child_error);
+                    continue;
+                }
+            };
+            let remove_with_parent = child.parent.borrow_mut().take().is_some_and(|parent| parent.remove_with_parent);
+            if remove_with_parent {
+                drop(child);
+                if let Err(child_error) = Entity::remove(server, child_obj) {
+                    error.get_or_insert(child_error);
+                }
+            } else {
+                child.sync_parent(server);
+            }
+        }
+        let on_remove = entity_type.call_callback("on_remove", entity_obj.clone());
+        let removed_event = server.call_event("entity_removed".into(), entity_obj.clone());
+        error.map_or(Ok(()), Err).and(on_remove).and(removed_event)
     }
 }
 impl UserData for Entity {
@@ -384,66 +1551,50 @@ impl UserData for Entity {
         });
         fields.add_field_function_set("position", |lua, entity_obj, position: Position| {
             let entity_obj = entity_obj.into_owned();
-            let entity = entity_obj.clone();
-            let entity = entity.borrow::<Entity>().unwrap();
-
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-
-            let old_position = entity.position.borrow().clone();
-            let old_chunk_position = old_position.align_to_tile().to_chunk_position().0;
-            let new_chunk_position = position.align_to_tile().to_chunk_position().0;
-            if old_position.world != position.world || old_chunk_position != new_chunk_position {
-                let old_viewers: HashSet<Uuid> = {
-                    let mut old_chunk = server.get_chunk(old_chunk_position, old_position.world);
-                    old_chunk.entities.remove(&entity.uuid);
-                    let v = old_chunk.viewers.borrow().keys().cloned().collect();
-                    v
-                };
-                let new_viewers: HashSet<Uuid> = {
-                    let mut new_chunk = server.get_chunk(new_chunk_position, position.world.clone());
-                    new_chunk.entities.insert(entity.uuid, entity_obj);
-                    let v = new_chunk.viewers.borrow().keys().cloned().collect();
-                    v
-                };
-                for new_viewer in new_viewers.difference(&old_viewers) {
-                    server.try_send_message_to(*new_viewer, MessageS2C::AddEntity(entity.create_add_message(&server)));
+            let mut chunk_changes = Vec::new();
+            let moved = {
+                let entity = entity_obj.borrow::<Entity>()?;
+                if entity.is_removed() {
+                    return Err(Error::runtime("can't move removed entity"));
                 }
-                for viewer in new_viewers.intersection(&old_viewers) {
-                    server.try_send_message_to(*viewer, MessageS2C::UpdateEntityPosition(entity.uuid, Vec2 { x: position.x, y: position.y }));
-                }
-                for old_viewer in old_viewers.difference(&new_viewers) {
-                    server.try_send_message_to(*old_viewer, MessageS2C::RemoveEntity(entity.uuid.clone()));
-                }
-            } else {
-                for viewer in server.get_chunk(position.align_to_tile().to_chunk_position().0, position.world.clone()).viewers.borrow().keys() {
-                    server.try_send_message_to(*viewer, MessageS2C::UpdateEntityPosition(entity.uuid, Vec2 { x: position.x, y: position.y }));
+                let attached = if let Some(parent) = entity.parent.borrow_mut().as_mut() {
+                    let parent_position = server.entities.borrow().get(&parent.uuid).unwrap().borrow::<Entity>()?.position.borrow().clone();
+                    if parent_position.world != position.world {
+                        return Err(Error::runtime("attached entity can't leave its parent's world"));
+                    }
+                    parent.offset = Vec2 { x: position.x - parent_position.x, y: position.y - parent_position.y };
+                    true
+                } else {
+                    false
+                };
+                let moved = entity.set_position(&server, &entity_obj, position, &mut chunk_changes);
+                if attached {
+                    entity.sync_parent(&server);
                 }
-            }
-            *entity.position.borrow_mut() = position;
-            Ok(())
+                moved
+            };
+            Entity::call_chunk_change_callbacks(&server, chunk_changes)?;
+            moved
         });
         fields.add_field_method_set("animation", |lua, entity, animation: String| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            let animation_id = animation.into();
-            if server.entity_registry.entities.get(&entity.type_id).unwrap().animations.contains_key(&animation_id) {
-                return Err(Error::runtime("animation doesn't exist"))?;
-            }
-            {
-                let mut animation = entity.animation.borrow_mut();
-                animation.animation = animation_id;
-                animation.begin_time = server.ticks_passed.get();
-            }
-            entity.sync_animations(&server);
-            Ok(())
+            entity.play(&server, animation.into(), None)
         });
         fields.add_field_method_get("animation", |lua, entity|{
             Ok(entity.animation.borrow().animation.to_string())
         });
         fields.add_field_method_set("animation_time", |lua, entity, time: f64|{
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            if entity.is_removed() {
+                return Err(Error::runtime("can't animate removed entity"));
+            }
             {
                 let mut animation = entity.animation.borrow_mut();
-                animation.begin_time = server.ticks_passed.get()-(time*Server::TPS as f64) as u32;
+                animation.begin_time = server.ticks_passed.get().saturating_sub((time * Server::TPS as f64) as u32);
+                let data = server.entity_registry.entities.get(&entity.type_id).unwrap().animations.get(&animation.animation);
+                animation.processed_step = data.and_then(|data| data.step_at(animation.running_for(&server)).checked_sub(1));
+                animation.ended = false;
             }
             entity.sync_animations(&server);
             Ok(())
@@ -452,33 +1603,298 @@ impl UserData for Entity {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
             Ok((server.ticks_passed.get()-entity.animation.borrow().begin_time) as f64/Server::TPS as f64)
         });
+        fields.add_field_method_set("rotation", |lua, entity, rotation: f32| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.update_visuals(&server, |visuals| visuals.rotation = rotation);
+            Ok(())
+        });
+        fields.add_field_method_get("rotation", |lua, entity| {
+            Ok(entity.visuals.borrow().rotation)
+        });
+        fields.add_field_method_set("scale", |lua, entity, scale: Value| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let scale = match scale {
+                Value::Table(scale) => (scale.get("x")?, scale.get("y")?),
+                scale => {
+                    let scale = f32::from_lua(scale, lua)?;
+                    (scale, scale)
+                }
+            };
+            entity.update_visuals(&server, |visuals| visuals.scale = scale);
+            Ok(())
+        });
+        fields.add_field_method_get("scale", |lua, entity| {
+            let scale = entity.visuals.borrow().scale;
+            let table = lua.create_table()?;
+            table.set("x", scale.0)?;
+            table.set("y", scale.1)?;
+            Ok(table)
+        });
+        fields.add_field_method_set("tint", |lua, entity, tint: Table| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let tint = (tint.get("r")?, tint.get("g")?, tint.get("b")?, tint.get::<_, Option<u8>>("a")?.unwrap_or(255));
+            entity.update_visuals(&server, |visuals| visuals.tint = tint);
+            Ok(())
+        });
+        fields.add_field_method_get("tint", |lua, entity| {
+            let tint = entity.visuals.borrow().tint;
+            let table = lua.create_table()?;
+            table.set("r", tint.0)?;
+            table.set("g", tint.1)?;
+            table.set("b", tint.2)?;
+            table.set("a", tint.3)?;
+            Ok(table)
+        });
+        fields.add_field_method_set("flip_x", |lua, entity, flip_x: bool| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.update_visuals(&server, |visuals| visuals.flip_x = flip_x);
+            Ok(())
+        });
+        fields.add_field_method_get("flip_x", |lua, entity| {
+            Ok(entity.visuals.borrow().flip_x)
+        });
+        fields.add_field_method_set("flip_y", |lua, entity, flip_y: bool| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.update_visuals(&server, |visuals| visuals.flip_y = flip_y);
+            Ok(())
+        });
+        fields.add_field_method_get("flip_y", |lua, entity| {
+            Ok(entity.visuals.borrow().flip_y)
+        });
+        fields.add_field_method_set("z", |lua, entity, z: i32| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.update_visuals(&server, |visuals| visuals.z = z);
+            Ok(())
+        });
+        fields.add_field_method_get("z", |lua, entity| {
+            Ok(entity.visuals.borrow().z)
+        });
+        fields.add_field_method_set("visible", |lua, entity, visible: bool| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.update_visuals(&server, |visuals| visuals.visible = visible);
+            Ok(())
+        });
+        fields.add_field_method_get("visible", |lua, entity| {
+            Ok(entity.visuals.borrow().visible)
+        });
+        fields.add_field_method_set("owner", |lua, entity, owner: Option<OwnedAnyUserData>| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let owner = owner.map(|owner| owner.borrow::<Client>().map(|owner| owner.id)).transpose()?;
+            entity.visibility.borrow_mut().owner = owner;
+            entity.refresh_visibility(&server)
+        });
+        fields.add_field_method_get("owner", |lua, entity| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let owner = entity.visibility.borrow().owner.and_then(|owner| server.clients.borrow().get(&owner).cloned());
+            Ok(owner)
+        });
+        fields.add_field_method_set("owner_only", |lua, entity, owner_only: bool| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.visibility.borrow_mut().owner_only = owner_only;
+            entity.refresh_visibility(&server)
+        });
+        fields.add_field_method_get("owner_only", |lua, entity| {
+            Ok(entity.visibility.borrow().owner_only)
+        });
         fields.add_field_method_get("id", |lua, entity| {
             Ok(entity.uuid.to_string())
         });
+        fields.add_field_method_get("parent", |lua, entity| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let parent = entity.parent.borrow().as_ref().and_then(|parent| server.entities.borrow().get(&parent.uuid).cloned());
+            Ok(parent)
+        });
+        fields.add_field_method_get("children", |lua, entity| {
+            Ok(entity.children.borrow().values().cloned().collect::<Vec<_>>())
+        });
+        fields.add_field_method_get("tags", |lua, entity| {
+            Ok(entity.tags.borrow().iter().map(|tag| tag.to_string()).collect::<Vec<_>>())
+        });
         fields.add_field_method_get("removed", |lua, entity| {
             Ok(entity.removed.load(Ordering::SeqCst))
         });
     }
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("remove", |lua, entity, args: ()| {
+        methods.add_function("remove", |lua, entity_obj: OwnedAnyUserData| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            Entity::remove(&server, &entity_obj)
+        });
+        methods.add_function("remove_after", |lua, (entity_obj, seconds): (OwnedAnyUserData, f64)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            server.schedule_task(move |server| {
+                if let Err(error) = Entity::remove(server, &entity_obj) {
+                    eprintln!("error removing entity: {}", error);
+                }
+                None
+            }, seconds);
+            Ok(())
+        });
+        methods.add_function("attach", |lua, (parent_obj, child_obj, offset, remove_with_parent): (OwnedAnyUserData, OwnedAnyUserData, Value, Option<bool>)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let offset = match offset {
+                Value::Nil => Vec2::default(),
+                Value::Table(offset) => Vec2 { x: offset.get("x")?, y: offset.get("y")? },
+                offset => {
+                    let offset = Position::from_lua(offset, lua)?;
+                    Vec2 { x: offset.x, y: offset.y }
+                }
+            };
+            let (parent_uuid, parent_position) = {
+                let parent = parent_obj.borrow::<Entity>()?;
+                let parent_position = parent.position.borrow().clone();
+                (parent.uuid, parent_position)
+            };
+            let child = child_obj.borrow::<Entity>()?;
+            if child.is_removed() || parent_obj.borrow::<Entity>()?.is_removed() {
+                return Err(Error::runtime("can't attach removed entity"));
+            }
+            let mut ancestor = Some(parent_uuid);
+            while let Some(uuid) = ancestor {
+                if uuid == child.uuid {
+                    return Err(Error::runtime("can't attach entity to itself or its own child"));
+                }
+                ancestor = match server.entities.borrow().get(&uuid) {
+                    Some(ancestor_obj) => {
+                        let ancestor = ancestor_obj.borrow::<Entity>()?;
+                        let parent = ancestor.parent.borrow().as_ref().map(|parent| parent.uuid);
+                        parent
+                    }
+                    None => None,
+                };
+            }
+            child.unlink_from_parent(&server);
+            *child.parent.borrow_mut() = Some(EntityParent {
+                uuid: parent_uuid,
+                offset,
+                remove_with_parent: remove_with_parent.unwrap_or(false),
+            });
+            parent_obj.borrow::<Entity>()?.children.borrow_mut().insert(child.uuid, child_obj.clone());
+            let mut chunk_changes = Vec::new();
+            let moved = child.set_position(&server, &child_obj, Position {
+                x: parent_position.x + offset.x,
+                y: parent_position.y + offset.y,
+                world: parent_position.world.clone(),
+            }, &mut chunk_changes);
+            child.sync_parent(&server);
+            drop(child);
+            Entity::call_chunk_change_callbacks(&server, chunk_changes)?;
+            moved
+        });
+        methods.add_method("play", |lua, entity, (animation, options): (String, Option<Table>)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let next = match options {
+                Some(options) => options.get::<_, Option<String>>("then")?.map(|next| next.into()),
+                None => None,
+            };
+            entity.play(&server, animation.into(), next)
+        });
+        methods.add_method("set_visible_to", |lua, entity, (client, visible): (OwnedAnyUserData, bool)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let client = client.borrow::<Client>()?.id;
+            {
+                let mut visibility = entity.visibility.borrow_mut();
+                if visible {
+                    visibility.hidden.remove(&client);
+                    if let Some(allowed) = &mut visibility.allowed {
+                        allowed.insert(client);
+                    }
+                } else {
+                    visibility.hidden.insert(client);
+                }
+            }
+            entity.refresh_visibility(&server)
+        });
+        methods.add_method("show_only_to", |lua, entity, clients: Option<Vec<OwnedAnyUserData>>| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let allowed = match clients {
+                Some(clients) => Some(clients.iter().map(|client| client.borrow::<Client>().map(|client| client.id)).collect::<mlua::Result<_>>()?),
+                None => None,
+            };
+            entity.visibility.borrow_mut().allowed = allowed;
+            entity.refresh_visibility(&server)
+        });
+        methods.add_method("set_visibility_predicate", |lua, entity, predicate: Option<OwnedFunction>| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            entity.visibility.borrow_mut().predicate = predicate;
+            entity.refresh_visibility(&server)
+        });
+        methods.add_method("refresh_visibility", |lua, entity, ()| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            server.entities.borrow_mut().remove(&entity.uuid);
-            let position = entity.position.borrow().clone();
-            let chunk = position.align_to_tile().to_chunk_position().0;
-            let mut chunk = server.get_chunk(chunk, position.world);
-            chunk.entities.remove(&entity.uuid);
-            entity.removed.load(Ordering::SeqCst);
-            for viewer in chunk.viewers.borrow().keys() {
-                server.try_send_message_to(*viewer, MessageS2C::RemoveEntity(entity.uuid));
+            entity.refresh_visibility(&server)
+        });
+        methods.add_function("teleport", |lua, (entity_obj, position): (OwnedAnyUserData, Position)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            if !server.world_exists(&position.world) {
+                return Err(Error::runtime(format!("world {} doesn't exist", position.world)));
+            }
+            let mut chunk_changes = Vec::new();
+            let moved = {
+                let entity = entity_obj.borrow::<Entity>()?;
+                if entity.is_removed() {
+                    return Err(Error::runtime("can't teleport removed entity"));
+                }
+                //a teleported entity leaves its parent, its own children come along
+                let detached = entity.unlink_from_parent(&server).is_some();
+                let moved = entity.set_position(&server, &entity_obj, position, &mut chunk_changes);
+                if detached {
+                    entity.sync_parent(&server);
+                }
+                moved
+            };
+            //cameras following a moved entity switch over now rather than on their client's next tick
+            let moved_entities: HashSet<Uuid> = chunk_changes.iter().map(|(moved, _, _)| moved.borrow::<Entity>().map(|moved| moved.uuid)).collect::<mlua::Result<_>>()?;
+            for client_obj in server.clients.borrow().values() {
+                let Ok(mut client) = client_obj.borrow_mut::<Client>() else {
+                    continue;
+                };
+                let follows_moved = match &client.camera {
+                    ClientCameraType::Entity(camera_entity) => camera_entity.borrow::<Entity>().map(|camera_entity| moved_entities.contains(&camera_entity.uuid)).unwrap_or(false),
+                    _ => false,
+                };
+                if follows_moved {
+                    let camera = client.camera.clone();
+                    client.set_camera(&server, client_obj.clone(), camera)?;
+                }
+            }
+            Entity::call_chunk_change_callbacks(&server, chunk_changes)?;
+            moved
+        });
+        methods.add_method("detach", |lua, entity, ()| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            if entity.unlink_from_parent(&server).is_some() {
+                entity.sync_parent(&server);
             }
             Ok(())
         });
+        methods.add_function("add_tag", |lua, (entity_obj, tag): (OwnedAnyUserData, String)| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let entity = entity_obj.borrow::<Entity>()?;
+            let tag: ImmutableString = tag.into();
+            entity.tags.borrow_mut().insert(tag.clone());
+            if !entity.removed.load(Ordering::SeqCst) {
+                server.tagged_entities.borrow_mut().entry(tag).or_insert_with(HashMap::new).insert(entity.uuid, entity_obj.clone());
+            }
+            Ok(())
+        });
+        methods.add_method("remove_tag", |lua, entity, tag: String| {
+            let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let tag: ImmutableString = tag.into();
+            entity.tags.borrow_mut().remove(&tag);
+            if let Some(tagged) = server.tagged_entities.borrow_mut().get_mut(&tag) {
+                tagged.remove(&entity.uuid);
+            }
+            Ok(())
+        });
+        methods.add_method("has_tag", |lua, entity, tag: String| {
+            Ok(entity.tags.borrow().contains(&ImmutableString::from(tag)))
+        });
         methods.add_method("get_collider", |lua, entity, name: String| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            let aabb = server.entity_registry.entities.get(&entity.type_id).unwrap().colliders.get::<ImmutableString>(&name.into()).unwrap().aabb;
+            let collider = server.entity_registry.entities.get(&entity.type_id).unwrap().colliders.get::<ImmutableString>(&name.into()).ok_or(Error::runtime("collider doesn't exist"))?;
             Ok(LuaAABB {
-                aabb: aabb.offset(&*entity.position.borrow()),
+                aabb: collider.aabb.offset(&*entity.position.borrow()),
                 world: entity.position.borrow().world.clone(),
+                mask: collider.collides_with.unwrap_or_else(|| server.collision_layers.collides_with(collider.mask)),
             })
         });
         methods.add_meta_function("__index", |lua, (entity, key): (AnyUserData, Value)| {
@@ -490,19 +1906,32 @@ impl UserData for Entity {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, FromLua)]
 pub struct LuaAABB {
     aabb: AABB,
     world: ImmutableString,
+    mask: u32,
 }
 impl LuaAABB{
     pub fn collides(&self, server: &Server, mask: u32) -> bool{
+        self.collides_from(server, mask, None)
+    }
+    pub fn collides_from(&self, server: &Server, mask: u32, previous: Option<&AABB>) -> bool{
+        let worlds = server.worlds.borrow();
+        let Some(world) = worlds.get(&self.world) else {
+            return false;
+        };
         for tile in self.aabb.tiles_overlapping() {
             let (chunk_position, chunk_offset) = tile.to_chunk_position();
-            let chunk = server.get_chunk(chunk_position, self.world.clone());
-            for (tileset, tile_layer) in chunk.tile_layers.iter() {
-                let tile_type = server.tile_sets.get(tileset).unwrap().by_id(tile_layer.0[chunk_offset.x as usize + (chunk_offset.y as usize * CHUNK_SIZE as usize)]).unwrap();
-                if tile_type.collision_mask & mask != 0 {
+            let Some(chunk) = world.chunks.get(&chunk_position) else {
+                continue;
+            };
+            for tile_layer in chunk.tile_layers.values() {
+                let cell = tile_layer.0[chunk_offset.index()];
+                let Some(tile_type) = server.tile_type(cell) else {
+                    continue;
+                };
+                if tile_type.collision_mask & mask != 0 && tile_type.collision_shapes.iter().any(|shape| shape.collides(tile, cell.orientation, &self.aabb, previous)) {
                     return true;
                 }
             }
@@ -543,8 +1972,9 @@ impl UserData for LuaAABB {
             }
             Ok(table)
         });
-        methods.add_method("test_collisions", |lua: &Lua, aabb, mask: u32| {
+        methods.add_method("test_collisions", |lua: &Lua, aabb, mask: Option<CollisionMask>| {
             let mut server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
+            let mask = mask.map(|mask| mask.0).unwrap_or(aabb.mask);
             let mut collided = false;
             for entity in server.entities.borrow().values() {
                 let entity: std::cell::Ref<Entity> = entity.borrow().unwrap();
@@ -562,7 +1992,11 @@ impl UserData for LuaAABB {
             collided |= aabb.collides(&server, mask);
             Ok(collided)
         });
-        methods.add_method("test_sweep", |lua: &Lua, aabb, (mask, target_position): (u32, Position)| {
+        methods.add_method("test_sweep", |lua: &Lua, aabb, (mask, target_position): (Value, Option<Position>)| {
+            let (mask, target_position) = match target_position {
+                Some(target_position) => (CollisionMask::from_lua(mask, lua)?.0, target_position),
+                None => (aabb.mask, Position::from_lua(mask, lua)?),
+            };
             if target_position.world != aabb.world {
                 return Err(Error::runtime("mismatched world"));
             }
@@ -585,14 +2019,16 @@ impl UserData for LuaAABB {
                 collision_time = 0.;
             }
             let movement = Vec2{x: (target_position.x-aabb.aabb.x)/5., y: (target_position.y-aabb.aabb.y)/5.};
+            let mut previous = aabb.aabb;
             for i in 0..5 {
                 let mut aabb = aabb.clone();
                 aabb.aabb.x += movement.x*(i+1) as f64;
                 aabb.aabb.y += movement.y*(i+1) as f64;
-                if aabb.collides(&server, mask){
+                if aabb.collides_from(&server, mask, Some(&previous)){
                     collision_time = collision_time.min(i as f64/5.);
                     break;
                 }
+                previous = aabb.aabb;
             }
             //todo: this is stupid
             Ok((collision_time, Position{
@@ -607,6 +2043,8 @@ impl UserData for LuaAABB {
 pub struct Client {
     pub(crate) connection: ClientConnection,
     camera: ClientCameraType,
+    /// World and chunks the client was last sent, an entity camera may have moved away from them since.
+    loaded_chunks: (ImmutableString, HashSet<ChunkPosition>),
     pub(crate) closed: bool,
     pub id: Uuid,
     player_input: PlayerInputMessage,
@@ -616,6 +2054,7 @@ impl Client {
         let user_data = lua.create_userdata(Client {
             connection,
             camera: ClientCameraType::None,
+            loaded_chunks: ("".into(), HashSet::new()),
             id: Uuid::new_v4(),
             closed: false,
             player_input: PlayerInputMessage::default(),
@@ -624,43 +2063,114 @@ impl Client {
         user_data.to_ref().set_nth_user_value(2, table).unwrap();
         Ok(user_data)
     }
-    pub fn set_camera(&mut self, server: &Server, lua_ref: OwnedAnyUserData, new_camera: ClientCameraType) {
-        let old = self.camera.get_loaded_chunks();
+    /// Moves the camera, chunks are always fully streamed, a failing visibility predicate is reported afterwards.
+    pub fn set_camera(&mut self, server: &Server, lua_ref: OwnedAnyUserData, new_camera: ClientCameraType) -> mlua::Result<()> {
+        if let Some(position) = new_camera.get_position() {
+            if !server.world_exists(&position.world) {
+                return Err(Error::runtime(format!("world {} doesn't exist", position.world)));
+            }
+        }
         let new = new_camera.get_loaded_chunks();
+        let old = std::mem::replace(&mut self.loaded_chunks, new.clone());
+        let mut loaded = Ok(());
         if old.0 == new.0 {
             for old_chunk_position in old.1.difference(&new.1) {
-                let old_chunk = server.get_chunk(*old_chunk_position, old.0.clone());
-                old_chunk.viewers.borrow_mut().remove(&self.id);
-                self.connection.sender.send(MessageS2C::UnloadChunk(*old_chunk_position, old_chunk.entities.keys().cloned().collect())).expect("TODO: panic message");
+                self.unload_chunk(server, *old_chunk_position, old.0.clone());
             }
             for new_chunk_position in new.1.difference(&old.1) {
-                let new_chunk = server.get_chunk(*new_chunk_position, new.0.clone());
-                new_chunk.viewers.borrow_mut().insert(self.id, lua_ref.clone());
-                let _ = self.connection.sender.send(MessageS2C::LoadChunk(*new_chunk_position,
-                                                                          new_chunk.tile_layers.iter().map(|(key, value)| (key.to_string(), value.0.clone())).collect(),
-                                                                          new_chunk.entities.values().map(|entity| entity.borrow::<Entity>().unwrap().create_add_message(server)).collect(),
-                ));
+                loaded = loaded.and(self.load_chunk(server, &lua_ref, *new_chunk_position, new.0.clone()));
             }
         } else {
             for old_chunk_position in old.1 {
-                let old_chunk = server.get_chunk(old_chunk_position, old.0.clone());
-                old_chunk.viewers.borrow_mut().remove(&self.id);
-                let _ = self.connection.sender.send(MessageS2C::UnloadChunk(old_chunk_position, old_chunk.entities.keys().cloned().collect()));
+                self.unload_chunk(server, old_chunk_position, old.0.clone());
             }
-            for new_chunk_position in new.1 {
-                let new_chunk = server.get_chunk(new_chunk_position, new.0.clone());
-                new_chunk.viewers.borrow_mut().insert(self.id, lua_ref.clone());
-                let _ = self.connection.sender.send(MessageS2C::LoadChunk(new_chunk_position,
-                                                                          new_chunk.tile_layers.iter().map(|(key, value)| (key.to_string(), value.0.clone())).collect(),
-                                                                          new_chunk.entities.values().map(|entity| entity.borrow::<Entity>().unwrap().create_add_message(server)).collect(),
-                ));
+            for new_chunk_position in &new.1 {
+                loaded = loaded.and(self.load_chunk(server, &lua_ref, *new_chunk_position, new.0.clone()));
             }
         }
+        if old.0 != new.0 && !new.1.is_empty() {
+            let worlds = server.worlds.borrow();
+            let world = worlds.get(&new.0);
+            let _ = self.connection.sender.send(world.map(|world| world.layers_message()).unwrap_or_else(|| MessageS2C::SetWorldLayers(Vec::new())));
+            let _ = self.connection.sender.send(world.map(|world| world.background_message()).unwrap_or_else(|| MessageS2C::SetBackground(WorldBackground::default(), HashMap::new())));
+        }
         let camera_position = new_camera.get_position();
         if let Some(camera_position) = camera_position {
             let _ = self.connection.sender.send(MessageS2C::CameraInfo(Vec2 { x: camera_position.x, y: camera_position.y }));
         }
         self.camera = new_camera;
+        loaded
+    }
+    fn unload_chunk(&self, server: &Server, position: ChunkPosition, world: ImmutableString) {
+        let entities: Vec<OwnedAnyUserData> = {
+            //the chunk is already gone when its world was deleted
+            let Ok(old_chunk) = server.get_chunk(position, world) else {
+                let _ = self.connection.sender.send(MessageS2C::UnloadChunk(position, Vec::new()));
+                return;
+            };
+            old_chunk.viewers.borrow_mut().remove(&self.id);
+            let _ = self.connection.sender.send(MessageS2C::UnloadChunk(position, old_chunk.entities.keys().cloned().collect()));
+            let entities = old_chunk.entities.values().cloned().collect();
+            entities
+        };
+        for entity in entities {
+            if let Ok(entity) = entity.borrow::<Entity>() {
+                entity.shown_to.borrow_mut().remove(&self.id);
+            }
+        }
+    }
+    fn load_chunk(&self, server: &Server, lua_ref: &OwnedAnyUserData, position: ChunkPosition, world: ImmutableString) -> mlua::Result<()> {
+        let (tile_layers, tile_data, entities): (HashMap<String, Vec<TileCell>>, Vec<MessageS2C>, Vec<OwnedAnyUserData>) = {
+            let new_chunk = server.get_chunk(position, world)?;
+            new_chunk.viewers.borrow_mut().insert(self.id, lua_ref.clone());
+            let chunk_data = (
+                new_chunk.tile_layers.iter().map(|(layer, tile_layer)| (layer.to_string(), tile_layer.0.clone())).collect(),
+                new_chunk.tile_layers.iter().flat_map(|(layer, tile_layer)| tile_layer.replicated_data_messages(server, position, layer)).collect(),
+                new_chunk.entities.values().cloned().collect(),
+            );
+            chunk_data
+        };
+        let mut visible = Ok(());
+        let mut entity_messages = Vec::new();
+        for entity in &entities {
+            let entity = entity.borrow::<Entity>().unwrap();
+            match entity.can_be_seen_by(server, self.id) {
+                Ok(true) => {
+                    entity_messages.push(entity.create_add_message(server));
+                    entity.shown_to.borrow_mut().insert(self.id);
+                }
+                Ok(false) => {}
+                Err(error) => visible = visible.and(Err(error)),
+            }
+        }
+        let _ = self.connection.sender.send(MessageS2C::LoadChunk(position, tile_layers, entity_messages));
+        for message in tile_data {
+            let _ = self.connection.sender.send(message);
+        }
+        visible
+    }
+    /// Drops the camera if it looks at `world`, so the world can be deleted.
+    pub fn leave_world(&mut self, server: &Server, lua_ref: OwnedAnyUserData, world: &ImmutableString) -> mlua::Result<()> {
+        if !self.loaded_chunks.1.is_empty() && self.loaded_chunks.0 == *world {
+            self.set_camera(server, lua_ref, ClientCameraType::None)?;
+        }
+        Ok(())
+    }
+    /// Leaves the camera at `position` if it follows the removed entity `entity`,
+    /// or drops it when that world was deleted in the meantime.
+    fn release_camera_of(&mut self, server: &Server, lua_ref: OwnedAnyUserData, entity: Uuid, position: &Position) -> mlua::Result<()> {
+        let follows_entity = match &self.camera {
+            ClientCameraType::Entity(camera_entity) => camera_entity.borrow::<Entity>().map(|camera_entity| camera_entity.uuid == entity).unwrap_or(false),
+            _ => false,
+        };
+        if !follows_entity {
+            return Ok(());
+        }
+        let camera = match server.world_exists(&position.world) {
+            true => ClientCameraType::Position(position.clone()),
+            false => ClientCameraType::None,
+        };
+        self.set_camera(server, lua_ref, camera)
     }
     pub fn tick(&mut self, server: &Server, lua_ref: OwnedAnyUserData) {
         self.player_input = PlayerInputMessage::default();
@@ -688,7 +2198,9 @@ impl Client {
         }
         match &self.camera {
             ClientCameraType::Entity(_) => {
-                self.set_camera(server, lua_ref.clone(), self.camera.clone())
+                if let Err(error) = self.set_camera(server, lua_ref.clone(), self.camera.clone()) {
+                    eprintln!("visibility predicate error: {}", error);
+                }
             }
             _ => {}
         }
@@ -698,18 +2210,18 @@ impl UserData for Client {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_function("set_camera_position", |lua, (client, pos): (OwnedAnyUserData, Position)| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::Position(pos));
-            Ok(())
+            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::Position(pos))
         });
         methods.add_function("set_camera_entity", |lua, (client, entity): (OwnedAnyUserData, OwnedAnyUserData)| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::Entity(entity));
-            Ok(())
+            if entity.borrow::<Entity>()?.is_removed() {
+                return Err(Error::runtime("can't follow removed entity"));
+            }
+            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::Entity(entity))
         });
         methods.add_function("remove_camera", |lua, client: OwnedAnyUserData| {
             let server = lua.app_data_ref::<ServerPtr>().ok_or(Error::runtime("this method can only be used on running server"))?;
-            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::None);
-            Ok(())
+            client.borrow_mut::<Client>().unwrap().set_camera(&server, client.clone(), ClientCameraType::None)
         });
         methods.add_method("is_key_down", |lua, client, key: u16|{
             Ok(client.player_input.keys_down.contains(&key))
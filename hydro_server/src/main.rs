@@ -2,7 +2,7 @@
 
 use std::cell::{Cell, RefCell, RefMut};
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::{Duration, Instant};
@@ -11,7 +11,7 @@ use bincode::config;
 use bincode::error::DecodeError;
 use futures::{FutureExt, SinkExt, StreamExt};
 use immutable_string::ImmutableString;
-use mlua::{IntoLuaMulti, Lua, OwnedAnyUserData, Table};
+use mlua::{IntoLuaMulti, Lua, OwnedAnyUserData, Table, Value};
 use mlua::prelude::{LuaOwnedFunction, LuaOwnedTable};
 use tiled::{ChunkData, TileLayer};
 use tokio::runtime::Runtime;
@@ -21,14 +21,19 @@ use warp::{Filter, Sink};
 use warp::http::Response;
 use warp::ws::Message;
 
-use hydro_common::{AnimationData, EntityContentMessage, LoadContentMessage, MessageC2S, MessageS2C, TileSetContentMessage};
+use hydro_common::{AnimationData, AnimationFrame, EntityContentMessage, LoadContentMessage, MessageC2S, MessageS2C, TileAppearance, TileCell, TileFrame, TileOrientation, TileSetContentMessage, WorldBackground};
 use hydro_common::pos::{CHUNK_SIZE, ChunkOffset, ChunkPosition, TilePosition};
 
-use crate::lua::{load_tiled_properties_into_lua_table, Client, Collider, Position};
-use crate::util::AABB;
+use crate::lua::{load_tiled_collision_shape, load_tiled_properties_into_lua_table, Client, Collider, CollisionMask, Entity, LuaTileLayer, MapObject, Position, tile_data_value_from_lua, TileData};
+use crate::aseprite::AsepriteSheet;
+use crate::terrain::{NEIGHBOURS, Terrain};
+use crate::util::{AABB, png_size, TileCollisionShape};
 
 mod util;
 mod lua;
+mod aseprite;
+mod terrain;
+mod save;
 
 fn main() {
     let lua = Lua::new();
@@ -36,18 +41,33 @@ fn main() {
     InitEnvironment::load_into_lua(&lua);
     lua.load(std::fs::read_to_string("simple_mod.lua").unwrap()).exec().unwrap();
     let init_env = lua.remove_app_data::<InitEnvironment>().unwrap();
+    let mut tile_sets = init_env.tile_sets.into_inner();
+    //tile cells refer to tilesets by index, sorted so the numbering doesn't depend on hash order
+    let mut tileset_ids: Vec<ImmutableString> = tile_sets.keys().cloned().collect();
+    tileset_ids.sort();
+    for (index, name) in tileset_ids.iter().enumerate() {
+        tile_sets.get_mut(name).unwrap().index = index as u16;
+    }
     let (new_clients_tx, new_clients_rx) = std::sync::mpsc::channel();
     let server = Arc::new(Server {
         lua,
         worlds: RefCell::new(HashMap::new()),
-        tile_sets: init_env.tile_sets.into_inner(),
+        tile_sets,
+        tileset_ids,
         event_handlers: init_env.event_handlers.into_inner(),
         entity_registry: init_env.entity_registry.into_inner(),
+        collision_layers: init_env.collision_layers.into_inner(),
+        sprite_sheets: init_env.sprite_sheets.into_inner(),
         entities: RefCell::new(HashMap::new()),
+        tagged_entities: RefCell::new(HashMap::new()),
         new_clients: new_clients_rx,
         clients: RefCell::new(HashMap::new()),
         ticks_passed: Cell::new(0),
-        task_queue: RefCell::new(BinaryHeap::new())
+        task_queue: RefCell::new(BinaryHeap::new()),
+        tile_updates: RefCell::new(HashMap::new()),
+        neighbour_updates: RefCell::new(VecDeque::new()),
+        random_tick_speed: Cell::new(3),
+        random_state: Cell::new(Uuid::new_v4().as_u64_pair().0 | 1),
     });
     server.lua.set_app_data(server.clone());
 
@@ -67,17 +87,23 @@ fn main() {
         while let Ok(client) = server.new_clients.try_recv() {
             client.sender.send(MessageS2C::LoadContent(LoadContentMessage {
                 name: "hydro".to_string(),
-                tilesets: server.tile_sets.iter().map(|(key, value)| (key.to_string(), TileSetContentMessage {
-                    asset: value.asset.0.clone(),
-                    size: value.asset.1,
-                    tiles: value.tile_ids.iter().map(|id| value.tiles.get(id).unwrap().asset_position).collect(),
-                })).collect(),
+                tilesets: server.tileset_ids.iter().map(|name| {
+                    let value = server.tile_sets.get(name).unwrap();
+                    TileSetContentMessage {
+                        name: name.to_string(),
+                        asset: value.asset.0.clone(),
+                        size: value.asset.1,
+                        tiles: value.tile_ids.iter().map(|id| value.tiles.get(id).unwrap().appearance()).collect(),
+                    }
+                }).collect(),
+                sprite_sheets: server.sprite_sheets.iter().map(|(key, value)| (key.to_string(), value.clone())).collect(),
                 entities: server.entity_registry.entities.iter().map(|(key, value)| {
                     (key.to_string(), EntityContentMessage {
                         size: value.size,
                         animations: value.animations.iter().map(|(key, value)| (key.to_string(), value.clone())).collect(),
                     })
                 }).collect(),
+                time: server.ticks_passed.get() as f64 / Server::TPS as f64,
             })).unwrap();
             let client = Client::new(&server.lua, client).unwrap();
             let id = { client.borrow::<Client>().unwrap().id.clone() };
@@ -153,6 +179,8 @@ pub struct InitEnvironment {
     tile_sets: RefCell<HashMap<ImmutableString, TileSet>>,
     entity_registry: RefCell<EntityRegistry>,
     event_handlers: RefCell<HashMap<ImmutableString, Vec<LuaOwnedFunction>>>,
+    collision_layers: RefCell<CollisionLayers>,
+    sprite_sheets: RefCell<HashMap<ImmutableString, Vec<u8>>>,
 }
 impl InitEnvironment {
     pub fn load_into_lua(lua: &Lua) {
@@ -160,6 +188,8 @@ impl InitEnvironment {
             tile_sets: RefCell::new(HashMap::new()),
             entity_registry: RefCell::new(EntityRegistry { entities: HashMap::new() }),
             event_handlers: RefCell::new(HashMap::new()),
+            collision_layers: RefCell::new(CollisionLayers::new()),
+            sprite_sheets: RefCell::new(HashMap::new()),
         });
 
         let globals = lua.globals();
@@ -168,16 +198,27 @@ impl InitEnvironment {
             init_env.event_handlers.borrow_mut().entry(name.into()).or_insert_with(Vec::new).push(function);
             Ok(())
         }).unwrap()).unwrap();
+        globals.set("register_collision_layer", lua.create_function(|lua, name: String| {
+            let init_env = lua.app_data_ref::<InitEnvironment>().ok_or(mlua::Error::runtime("this method can only be used during initialization"))?;
+            let layer = init_env.collision_layers.borrow_mut().register(name.into());
+            layer
+        }).unwrap()).unwrap();
+        globals.set("set_layers_collide", lua.create_function(|lua, (first, second, collides): (CollisionMask, CollisionMask, bool)| {
+            let init_env = lua.app_data_ref::<InitEnvironment>().ok_or(mlua::Error::runtime("this method can only be used during initialization"))?;
+            init_env.collision_layers.borrow_mut().set_collides(first.0, second.0, collides);
+            Ok(())
+        }).unwrap()).unwrap();
         globals.set("register_tileset", lua.create_function(|lua, (name, table): (String, Table)| {
             let init_env = lua.app_data_ref::<InitEnvironment>().ok_or(mlua::Error::runtime("this method can only be used during initialization"))?;
             let mut tile_sets = init_env.tile_sets.borrow_mut();
+            let assets_table: Table = table.get("asset").unwrap();
+            let file: String = assets_table.get("file").unwrap();
             let mut tile_set = TileSet::new({
-                let assets_table: Table = table.get("asset").unwrap();
-                let file: String = assets_table.get("file").unwrap();
                 let size: u8 = assets_table.get("size").unwrap();
-                let image_data = std::fs::read(format!("assets/{}.png", file)).unwrap();
+                let path = format!("assets/{}.png", file);
+                let image_data = std::fs::read(&path).map_err(|error| mlua::Error::runtime(format!("couldn't read {}: {}", path, error)))?;
                 (image_data, size)
-            });
+            }, file, table.get("tiled_tileset")?);
             tile_set.register(match table.get::<_, Option<Table>>("default").unwrap() {
                 Some(default) => default.into_owned(),
                 None => {
@@ -191,39 +232,92 @@ impl InitEnvironment {
                 let tile: Table = tile.unwrap();
                 tile_set.register(tile.into_owned()).unwrap();
             }
+            if let Some(terrains) = table.get::<_, Option<Table>>("terrains")? {
+                for pair in terrains.pairs::<String, Table>() {
+                    let (terrain_name, terrain) = pair?;
+                    tile_set.register_terrain(terrain_name.as_str().into(), Terrain::from_lua(&terrain_name, terrain)?)?;
+                }
+            }
             tile_sets.insert(name.into(), tile_set);
             Ok(())
         }).unwrap()).unwrap();
         globals.set("get_tilesets_from_mapfile", lua.create_function(|lua, (map): (String)|{
-            let mut map = tiled::Loader::new().load_tmx_map(map).unwrap();
+            let map = tiled::Loader::new().load_tmx_map(&map).map_err(|error| mlua::Error::runtime(format!("couldn't load map {}: {}", map, error)))?;
             let tilesets_table = lua.create_table().unwrap();
             for tileset in map.tilesets(){
                 let tileset_table = lua.create_table().unwrap();
                 let tileset_tiles_table = lua.create_table().unwrap();
                 let tileset_assets_table = lua.create_table().unwrap();
-                tileset_assets_table.set("file", tileset.image.as_ref().unwrap().source.to_str()).unwrap();
+                let image = tileset.image.as_ref().ok_or_else(|| mlua::Error::runtime(format!("tileset {} has no single image", tileset.name)))?;
+                tileset_assets_table.set("file", image.source.to_str()).unwrap();
                 tileset_assets_table.set("size", tileset.tile_width).unwrap();
                 for (id, tile) in tileset.tiles(){
                     let tile_table = lua.create_table().unwrap();
-                    load_tiled_properties_into_lua_table(lua, &tile_table, &tile.properties);
+                    load_tiled_properties_into_lua_table(lua, &tile_table, &tile.properties)?;
                     tile_table.set("id", format!("{}", id)).unwrap();
                     let asset_pos_table = lua.create_table().unwrap();
                     asset_pos_table.set("x", id%tileset.columns).unwrap();
                     asset_pos_table.set("y", id/tileset.columns).unwrap();
                     tile_table.set("asset_pos", asset_pos_table).unwrap();
+                    if let Some(animation) = &tile.animation {
+                        let animation_table = lua.create_table().unwrap();
+                        for frame in animation {
+                            let frame_table = lua.create_table().unwrap();
+                            frame_table.set("x", frame.tile_id % tileset.columns).unwrap();
+                            frame_table.set("y", frame.tile_id / tileset.columns).unwrap();
+                            frame_table.set("duration", frame.duration as f64 / 1000.).unwrap();
+                            animation_table.push(frame_table).unwrap();
+                        }
+                        tile_table.set("animation", animation_table).unwrap();
+                    }
+                    if let Some(collision) = &tile.collision {
+                        let collision_table = lua.create_table().unwrap();
+                        for object in collision.object_data() {
+                            if let Some(shape) = load_tiled_collision_shape(lua, object, tileset.tile_width as f64, tileset.tile_height as f64)? {
+                                collision_table.push(shape).unwrap();
+                            }
+                        }
+                        tile_table.set("collision_shape", collision_table).unwrap();
+                    }
                     tileset_tiles_table.push(tile_table).unwrap();
                 }
                 tileset_table.set("tiles", tileset_tiles_table).unwrap();
+                if !tileset.wang_sets.is_empty() {
+                    let terrains_table = lua.create_table()?;
+                    for wang_set in &tileset.wang_sets {
+                        Terrain::wang_set_to_lua(lua, &terrains_table, wang_set)?;
+                    }
+                    tileset_table.set("terrains", terrains_table)?;
+                }
                 tileset_table.set("asset", tileset_assets_table).unwrap();
+                tileset_table.set("tiled_tileset", tileset.name.as_str()).unwrap();
                 tilesets_table.set(tileset.name.clone(), tileset_table).unwrap();
             }
             Ok(tilesets_table)
         }).unwrap()).unwrap();
+        globals.set("register_sprite_sheet", lua.create_function(|lua, (name, file): (String, Option<String>)| {
+            let init_env = lua.app_data_ref::<InitEnvironment>().ok_or(mlua::Error::runtime("this method can only be used during initialization"))?;
+            let path = format!("assets/{}.png", file.as_ref().unwrap_or(&name));
+            let image = std::fs::read(&path).map_err(|error| mlua::Error::runtime(format!("couldn't read {}: {}", path, error)))?;
+            init_env.sprite_sheets.borrow_mut().insert(name.into(), image);
+            Ok(())
+        }).unwrap()).unwrap();
+        globals.set("aseprite_animations", lua.create_function(|lua, file: String| {
+            let sheet = AsepriteSheet::load(file.as_str())?;
+            let animations = lua.create_table()?;
+            for tag in sheet.tag_names() {
+                let animation = lua.create_table()?;
+                animation.set("aseprite", file.as_str())?;
+                animation.set("tag", tag)?;
+                animations.set(tag, animation)?;
+            }
+            Ok(animations)
+        }).unwrap()).unwrap();
         globals.set("register_entity", lua.create_function(|lua, (name, table): (String, Table)| {
             let init_env = lua.app_data_ref::<InitEnvironment>().ok_or(mlua::Error::runtime("this method can only be used during initialization"))?;
             let mut entity_registry = init_env.entity_registry.borrow_mut();
-            entity_registry.register(lua, name.into(), table.into_owned());
-            Ok(())
+            let result = entity_registry.register(lua, name.into(), table.into_owned(), &mut init_env.sprite_sheets.borrow_mut());
+            result
         }).unwrap()).unwrap();
     }
 }
@@ -250,17 +344,30 @@ impl Ord for Task{
 pub struct Server {
     worlds: RefCell<HashMap<ImmutableString, World>>,
     tile_sets: HashMap<ImmutableString, TileSet>,
+    tileset_ids: Vec<ImmutableString>,
     entity_registry: EntityRegistry,
+    collision_layers: CollisionLayers,
+    sprite_sheets: HashMap<ImmutableString, Vec<u8>>,
     event_handlers: HashMap<ImmutableString, Vec<LuaOwnedFunction>>,
     entities: RefCell<HashMap<Uuid, OwnedAnyUserData>>,
+    tagged_entities: RefCell<HashMap<ImmutableString, HashMap<Uuid, OwnedAnyUserData>>>,
     new_clients: Receiver<ClientConnection>,
     clients: RefCell<HashMap<Uuid, OwnedAnyUserData>>,
     lua: Lua,
     ticks_passed: Cell<u32>,
-    task_queue: RefCell<BinaryHeap<Task>>
+    task_queue: RefCell<BinaryHeap<Task>>,
+    /// Tiles changed this tick by world and chunk, then by layer and cell index.
+    tile_updates: RefCell<HashMap<ImmutableString, HashMap<ChunkPosition, HashMap<ImmutableString, HashMap<u16, TileCell>>>>>,
+    /// Changed cells whose neighbours still have to be notified, as world, layer and position.
+    neighbour_updates: RefCell<VecDeque<(ImmutableString, ImmutableString, TilePosition)>>,
+    /// Random ticks per layer of every active chunk each tick.
+    random_tick_speed: Cell<u32>,
+    random_state: Cell<u64>,
 }
 impl Server {
     pub const TPS: u8 = 30;
+    /// Neighbour updates handled per tick, the rest waits so chains of updates can't stall the server.
+    pub const MAX_NEIGHBOUR_UPDATES: usize = 16384;
     pub fn call_event<T: for<'a> IntoLuaMulti<'a> + Clone>(&self, id: ImmutableString, data: T) -> mlua::Result<()> {
         for event in self.event_handlers.get(&id).unwrap_or(&Vec::new()) {
             event.call(data.clone())?;
@@ -269,6 +376,9 @@ impl Server {
     }
     pub fn tick(&self) {
         self.call_event("tick".into(), self.lua.create_table().unwrap().into_owned()).unwrap();
+        self.tick_entities();
+        self.tick_animations();
+        self.random_tick_tiles();
         for client in self.clients.borrow().values() {
             client.borrow_mut::<Client>().unwrap().tick(self, client.clone());
         }
@@ -281,6 +391,162 @@ impl Server {
                 self.schedule_task(task.task, reschedule);
             }
         }
+        self.update_tile_neighbours();
+        self.send_tile_updates();
+    }
+    /// xorshift64, good enough for picking tiles to tick.
+    fn random(&self, bound: usize) -> usize {
+        let mut state = self.random_state.get();
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        self.random_state.set(state);
+        (state % bound as u64) as usize
+    }
+    pub fn set_random_tick_speed(&self, speed: u32) {
+        self.random_tick_speed.set(speed);
+    }
+    /// Reports an error of a tile callback, tile callbacks run outside of any script so nothing else could handle it.
+    fn report_tile_callback_error(callback: &str, world: &ImmutableString, tile_pos: TilePosition, error: mlua::Error) {
+        eprintln!("error in {} of tile at {}:{} in {}: {}", callback, tile_pos.x, tile_pos.y, world, error);
+    }
+    fn random_tick_tiles(&self) {
+        let speed = self.random_tick_speed.get();
+        let mut ticked = Vec::new();
+        {
+            let worlds = self.worlds.borrow();
+            for (world_name, world) in worlds.iter() {
+                for (chunk_position, chunk) in world.chunks.iter().filter(|(_, chunk)| chunk.is_active()) {
+                    for (layer, tile_layer) in &chunk.tile_layers {
+                        for _ in 0..speed {
+                            let index = self.random(tile_layer.0.len());
+                            let cell = tile_layer.0[index];
+                            if self.tile_type(cell).is_some_and(|tile_type| tile_type.has_callback("on_random_tick")) {
+                                let position = TilePosition::from_chunk_position(*chunk_position, ChunkOffset::from_index(index));
+                                ticked.push((world_name.clone(), layer.clone(), position, cell));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for (world, layer, position, cell) in ticked {
+            //an earlier callback this tick may have replaced the tile
+            if self.read_cells(&world, &layer, [position])[0] != cell {
+                continue;
+            }
+            if let Err(error) = self.tile_type(cell).unwrap().call_callback("on_random_tick", (Position::from_tile(position, world.clone()), LuaTileLayer::new(layer))) {
+                Server::report_tile_callback_error("on_random_tick", &world, position, error);
+            }
+        }
+    }
+    fn update_tile_neighbours(&self) {
+        for _ in 0..Server::MAX_NEIGHBOUR_UPDATES {
+            let Some((world, layer, changed)) = self.neighbour_updates.borrow_mut().pop_front() else {
+                break;
+            };
+            let neighbours: Vec<TilePosition> = NEIGHBOURS.iter()
+                .filter(|(x, y)| *x == 0 || *y == 0)
+                .map(|(x, y)| TilePosition { x: changed.x + x, y: changed.y + y })
+                .collect();
+            let cells = self.read_cells(&world, &layer, neighbours.iter().cloned());
+            for (neighbour, cell) in neighbours.into_iter().zip(cells) {
+                if let Some(tile_type) = self.tile_type(cell) {
+                    if let Err(error) = tile_type.call_callback("on_neighbour_changed", (Position::from_tile(neighbour, world.clone()), LuaTileLayer::new(layer.clone()), Position::from_tile(changed, world.clone()))) {
+                        Server::report_tile_callback_error("on_neighbour_changed", &world, neighbour, error);
+                    }
+                }
+            }
+        }
+    }
+    /// Replicates the key to the chunk's viewers if the tile type lists it in `replicate`, then calls `on_tile_data_changed`.
+    pub fn tile_data_changed(&self, world: ImmutableString, layer: ImmutableString, tile_pos: TilePosition, key: Value, old: Value, new: Value) -> mlua::Result<()> {
+        let cell = self.read_cells(&world, &layer, [tile_pos])[0];
+        let Some(tile_type) = self.tile_type(cell) else {
+            return Ok(());
+        };
+        if let Value::String(name) = &key {
+            let name = name.to_str()?;
+            if tile_type.replicated_data.contains(&name.into()) {
+                let value = tile_data_value_from_lua(&new)?;
+                let viewers: Vec<Uuid> = self.get_chunk(tile_pos.to_chunk_position().0, world.clone())?.viewers.borrow().keys().cloned().collect();
+                for viewer in viewers {
+                    self.try_send_message_to(viewer, MessageS2C::SetTileData(tile_pos, layer.to_string(), name.to_string(), value.clone()));
+                }
+            }
+        }
+        tile_type.call_callback("on_tile_data_changed", (Position::from_tile(tile_pos, world), LuaTileLayer::new(layer), key, old, new))
+    }
+    /// Calls `on_scheduled_update` of the tile after `after` seconds, unless the tile was replaced in the meantime.
+    pub fn schedule_tile_update(&self, tile_pos: TilePosition, world: ImmutableString, layer: ImmutableString, after: f64) {
+        let scheduled = self.read_cells(&world, &layer, [tile_pos])[0];
+        self.schedule_task(move |server| {
+            let cell = server.read_cells(&world, &layer, [tile_pos])[0];
+            if cell.is_empty() || cell.tileset != scheduled.tileset || cell.id != scheduled.id {
+                return None;
+            }
+            if let Err(error) = server.tile_type(cell).unwrap().call_callback("on_scheduled_update", (Position::from_tile(tile_pos, world.clone()), LuaTileLayer::new(layer.clone()))) {
+                Server::report_tile_callback_error("on_scheduled_update", &world, tile_pos, error);
+            }
+            None
+        }, after);
+    }
+    fn send_tile_updates(&self) {
+        let tile_updates = std::mem::take(&mut *self.tile_updates.borrow_mut());
+        for (world, chunks) in tile_updates {
+            for (chunk_position, layers) in chunks {
+                let Ok(chunk) = self.get_chunk(chunk_position, world.clone()) else {
+                    continue;
+                };
+                let viewers: Vec<Uuid> = chunk.viewers.borrow().keys().cloned().collect();
+                drop(chunk);
+                for viewer in viewers {
+                    self.try_send_message_to(viewer, Server::tile_update_message(chunk_position, &layers));
+                }
+            }
+        }
+    }
+    /// A lone change is sent as `SetTile`, anything more as one `SetTiles` for the chunk.
+    fn tile_update_message(chunk_position: ChunkPosition, layers: &HashMap<ImmutableString, HashMap<u16, TileCell>>) -> MessageS2C {
+        let mut changes = layers.iter().flat_map(|(layer, cells)| cells.iter().map(move |(index, cell)| (layer, *index, *cell)));
+        if let (Some((layer, index, cell)), None) = (changes.next(), changes.next()) {
+            let position = TilePosition::from_chunk_position(chunk_position, ChunkOffset::from_index(index as usize));
+            return MessageS2C::SetTile(position, layer.to_string(), cell);
+        }
+        MessageS2C::SetTiles(chunk_position, layers.iter().map(|(layer, cells)| (layer.to_string(), cells.iter().map(|(index, cell)| (*index, *cell)).collect())).collect())
+    }
+    fn report_entity_callback_error(callback: &str, entity: Uuid, error: mlua::Error) {
+        eprintln!("error in {} of entity {}: {}", callback, entity, error);
+    }
+    /// Calls `on_tick` of entities in active chunks, an error only skips the entity that raised it.
+    fn tick_entities(&self) {
+        let ticking: Vec<(Uuid, OwnedAnyUserData)> = self.worlds.borrow().values()
+            .flat_map(|world| world.chunks.values())
+            .filter(|chunk| chunk.is_active())
+            .flat_map(|chunk| chunk.entities.iter().map(|(uuid, entity)| (*uuid, entity.clone())))
+            .collect();
+        for (uuid, entity) in ticking {
+            let entity_type = {
+                let Ok(entity) = entity.borrow::<Entity>() else {
+                    continue;
+                };
+                if entity.is_removed() {
+                    continue;
+                }
+                self.entity_registry.entities.get(&entity.type_id).unwrap()
+            };
+            if let Err(error) = entity_type.call_callback("on_tick", entity) {
+                Server::report_entity_callback_error("on_tick", uuid, error);
+            }
+        }
+    }
+    fn tick_animations(&self) {
+        let entities: Vec<(Uuid, OwnedAnyUserData)> = self.entities.borrow().iter().map(|(uuid, entity)| (*uuid, entity.clone())).collect();
+        for (uuid, entity) in entities {
+            if let Err(error) = Entity::tick_animation(self, &entity) {
+                Server::report_entity_callback_error("animation callbacks", uuid, error);
+            }
+        }
     }
     fn get_next_scheduled_task(&self) -> Option<Task>{
         let mut task_queue = self.task_queue.borrow_mut();
@@ -296,39 +562,259 @@ impl Server {
             task: Box::new(task)
         });
     }
-    pub fn get_chunk(&self, position: ChunkPosition, world: ImmutableString) -> RefMut<Chunk> {
-        RefMut::map(self.worlds.borrow_mut(), |worlds| {
-            worlds.entry(world.clone()).or_insert_with(World::new).chunks.entry(position.clone()).or_insert_with(|| {
+    /// Chunk of an existing world, the chunk is created and generated when first used.
+    pub fn get_chunk(&self, position: ChunkPosition, world: ImmutableString) -> mlua::Result<RefMut<'_, Chunk>> {
+        RefMut::filter_map(self.worlds.borrow_mut(), |worlds| {
+            let World { chunks, generator, .. } = worlds.get_mut(&world)?;
+            Some(chunks.entry(position).or_insert_with(|| {
+                let generator = generator.clone();
+                let world = world.clone();
                 self.schedule_task(move |server|{
-                    server.call_event("load_chunk".into(), Position{
+                    let origin = Position{
                         x: (position.x as i32 * CHUNK_SIZE) as f64,
                         y: (position.y as i32 * CHUNK_SIZE) as f64,
                         world: world.clone()
-                    }).unwrap();
+                    };
+                    //the world may have been deleted before the chunk got generated
+                    if !server.worlds.borrow().contains_key(&world) {
+                        return None;
+                    }
+                    if let Some(generator) = &generator {
+                        if let Err(error) = generator.call::<_, ()>(origin.clone()) {
+                            eprintln!("error generating chunk {}:{} of {}: {}", position.x, position.y, world, error);
+                        }
+                    }
+                    server.call_event("load_chunk".into(), origin).unwrap();
                     None
                 }, 0.);
                 Chunk::new()
-            })
-        })
+            }))
+        }).map_err(|_| Server::unknown_world(&world))
+    }
+    fn unknown_world(world: &ImmutableString) -> mlua::Error {
+        mlua::Error::runtime(format!("world {} doesn't exist", world))
+    }
+    /// Creates a world, this is the only way worlds come to exist.
+    pub fn create_world(&self, name: ImmutableString, generator: Option<LuaOwnedFunction>, properties: LuaOwnedTable) -> mlua::Result<()> {
+        let mut worlds = self.worlds.borrow_mut();
+        if worlds.contains_key(&name) {
+            return Err(mlua::Error::runtime(format!("world {} already exists", name)));
+        }
+        worlds.insert(name, World::new(generator, properties));
+        Ok(())
+    }
+    /// Removes the world with all its entities, clients looking at it lose their camera.
+    pub fn delete_world(&self, name: &ImmutableString) -> mlua::Result<()> {
+        let entities: Vec<OwnedAnyUserData> = {
+            let worlds = self.worlds.borrow();
+            let world = worlds.get(name).ok_or_else(|| Server::unknown_world(name))?;
+            world.chunks.values().flat_map(|chunk| chunk.entities.values().cloned()).collect()
+        };
+        for entity in entities {
+            Entity::remove(self, &entity)?;
+        }
+        for client_obj in self.clients.borrow().values() {
+            if let Ok(mut client) = client_obj.borrow_mut::<Client>() {
+                client.leave_world(self, client_obj.clone(), name)?;
+            }
+        }
+        self.worlds.borrow_mut().remove(name);
+        self.tile_updates.borrow_mut().remove(name);
+        Ok(())
+    }
+    pub fn world_names(&self) -> Vec<ImmutableString> {
+        let mut names: Vec<ImmutableString> = self.worlds.borrow().keys().cloned().collect();
+        names.sort();
+        names
+    }
+    pub fn world_exists(&self, name: &ImmutableString) -> bool {
+        self.worlds.borrow().contains_key(name)
+    }
+    pub fn world_properties(&self, name: &ImmutableString) -> mlua::Result<LuaOwnedTable> {
+        let worlds = self.worlds.borrow();
+        let world = worlds.get(name).ok_or_else(|| Server::unknown_world(name))?;
+        Ok(world.properties.clone())
+    }
+    /// Changes the background of `world` through `update` and resends it to everyone looking at that world.
+    pub fn update_background(&self, world: ImmutableString, update: impl FnOnce(&mut World)) -> mlua::Result<()> {
+        let viewers = {
+            let mut worlds = self.worlds.borrow_mut();
+            let world = worlds.get_mut(&world).ok_or_else(|| Server::unknown_world(&world))?;
+            update(world);
+            world.viewers()
+        };
+        for viewer in viewers {
+            let message = self.worlds.borrow().get(&world).unwrap().background_message();
+            self.try_send_message_to(viewer, message);
+        }
+        Ok(())
     }
     pub fn try_send_message_to(&self, id: Uuid, message: MessageS2C){
         if let Some(client) = self.clients.borrow().get(&id) {
             let _ = client.borrow::<Client>().unwrap().connection.sender.send(message);
         }
     }
-    pub fn set_tile(&self, tile_pos: TilePosition, world: ImmutableString, tileset_id: ImmutableString, id: ImmutableString) -> mlua::Result<()>{
+    /// Declares a tile layer of `world` if it doesn't exist yet, `z` also updates an existing layer.
+    pub fn define_layer(&self, world: ImmutableString, name: ImmutableString, z: Option<i32>) -> mlua::Result<()> {
+        let viewers = {
+            let mut worlds = self.worlds.borrow_mut();
+            let world_data = worlds.get_mut(&world).ok_or_else(|| Server::unknown_world(&world))?;
+            match world_data.layers.iter_mut().find(|layer| layer.name == name) {
+                Some(layer) => match z {
+                    Some(z) if layer.z != z => layer.z = z,
+                    _ => return Ok(()),
+                },
+                None => world_data.layers.push(WorldLayer { name, z: z.unwrap_or(WorldLayer::DEFAULT_Z) }),
+            }
+            world_data.viewers()
+        };
+        for viewer in viewers {
+            let message = self.worlds.borrow().get(&world).unwrap().layers_message();
+            self.try_send_message_to(viewer, message);
+        }
+        Ok(())
+    }
+    pub fn tile_type(&self, cell: TileCell) -> Option<&TileType> {
+        if cell.is_empty() {
+            return None;
+        }
+        self.tile_sets.get(self.tileset_ids.get(cell.tileset as usize)?)?.by_id(cell.id)
+    }
+    /// Reference of the tile in `cell` in `tileset:tile` form.
+    pub fn tile_reference(&self, cell: TileCell) -> Option<String> {
+        let tileset = self.tileset_ids.get(cell.tileset as usize)?;
+        let tile = self.tile_sets.get(tileset)?.tile_ids.get(cell.id as usize)?;
+        Some(format!("{}:{}", tileset, tile))
+    }
+    pub fn tile_cell(&self, tileset_id: &ImmutableString, id: &ImmutableString, orientation: TileOrientation) -> mlua::Result<TileCell> {
+        let tileset = self.tile_sets.get(tileset_id).ok_or_else(|| mlua::Error::runtime(format!("tileset {} doesn't exist", tileset_id)))?;
+        let tile = tileset.tiles.get(id).ok_or_else(|| mlua::Error::runtime(format!("tile {} not found in tileset {}", id, tileset_id)))?;
+        Ok(TileCell { tileset: tileset.index, id: tile.id, orientation })
+    }
+    pub fn set_tile(&self, tile_pos: TilePosition, world: ImmutableString, layer: ImmutableString, tileset_id: ImmutableString, id: ImmutableString, orientation: TileOrientation) -> mlua::Result<()>{
+        let cell = self.tile_cell(&tileset_id, &id, orientation)?;
+        self.set_cell(tile_pos, world, layer, cell)
+    }
+    /// Places `cell` into the layer, `TileCell::EMPTY` removes the tile.
+    pub fn set_cell(&self, tile_pos: TilePosition, world: ImmutableString, layer: ImmutableString, cell: TileCell) -> mlua::Result<()>{
+        self.set_cells(world, layer, [(tile_pos, cell)])
+    }
+    /// Places cells into the layer one chunk at a time, viewers get the changes of every chunk together at the end of the tick.
+    pub fn set_cells(&self, world: ImmutableString, layer: ImmutableString, cells: impl IntoIterator<Item=(TilePosition, TileCell)>) -> mlua::Result<()>{
+        self.write_cells(world, layer, cells, true)
+    }
+    /// `set_cells`, optionally without queueing `on_neighbour_changed` for the changed cells, as when restoring a save.
+    fn write_cells(&self, world: ImmutableString, layer: ImmutableString, cells: impl IntoIterator<Item=(TilePosition, TileCell)>, notify_neighbours: bool) -> mlua::Result<()>{
+        let mut chunks: HashMap<ChunkPosition, Vec<(ChunkOffset, TileCell)>> = HashMap::new();
+        for (tile_pos, cell) in cells {
+            let (chunk_position, chunk_offset) = tile_pos.to_chunk_position();
+            chunks.entry(chunk_position).or_default().push((chunk_offset, cell));
+        }
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        //the world learns about a layer when one of its chunks first gets it, not on every write
+        let mut new_layer = false;
+        let mut tile_updates = self.tile_updates.borrow_mut();
+        for (chunk_position, cells) in chunks {
+            let mut chunk = self.get_chunk(chunk_position, world.clone())?;
+            new_layer |= !chunk.tile_layers.contains_key(&layer);
+            let tile_layer = chunk.tile_layers.entry(layer.clone()).or_insert_with(|| ChunkTileLayer::new());
+            let updates = tile_updates.entry(world.clone()).or_default().entry(chunk_position).or_default().entry(layer.clone()).or_default();
+            for (chunk_offset, cell) in cells {
+                if notify_neighbours && tile_layer.0[chunk_offset.index()] != cell {
+                    self.neighbour_updates.borrow_mut().push_back((world.clone(), layer.clone(), TilePosition::from_chunk_position(chunk_position, chunk_offset)));
+                }
+                tile_layer.0[chunk_offset.index()] = cell;
+                tile_layer.2.remove(&chunk_offset);
+                if let Some(tile_data) = tile_layer.1.remove(&chunk_offset) {
+                    tile_data.invalidate();
+                }
+                updates.insert(chunk_offset.index() as u16, cell);
+            }
+        }
+        if new_layer {
+            self.define_layer(world, layer, None)?;
+        }
+        Ok(())
+    }
+    /// Reads cells of a layer under a single borrow of the worlds, cells of chunks that don't exist are empty.
+    pub fn read_cells(&self, world: &ImmutableString, layer: &ImmutableString, positions: impl IntoIterator<Item=TilePosition>) -> Vec<TileCell> {
+        let worlds = self.worlds.borrow();
+        let world = worlds.get(world);
+        positions.into_iter().map(|position| {
+            let (chunk_position, chunk_offset) = position.to_chunk_position();
+            world.and_then(|world| world.chunks.get(&chunk_position))
+                .and_then(|chunk| chunk.tile_layers.get(layer))
+                .map(|tile_layer| tile_layer.0[chunk_offset.index()])
+                .unwrap_or_default()
+        }).collect()
+    }
+    pub fn terrain_at(&self, tile_pos: TilePosition, world: ImmutableString, layer: &ImmutableString) -> Option<(ImmutableString, ImmutableString)> {
         let (chunk_position, chunk_offset) = tile_pos.to_chunk_position();
-        let mut chunk = self.get_chunk(chunk_position, world);
-        let tile_layer = chunk.tile_layers.entry(tileset_id.clone()).or_insert_with(|| ChunkTileLayer::new());
-        let tileset = self.tile_sets.get(&tileset_id).ok_or(mlua::Error::runtime("tileset doesn't exist"))?;
-        let tile_id = tileset.tiles.get::<ImmutableString>(&id.into()).ok_or(mlua::Error::runtime("tile not found in tileset"))?.id;
-        tile_layer.0[chunk_offset.index()] = tile_id;
-        if let Some(tile_data) = tile_layer.1.remove(&chunk_offset) {
-            tile_data.to_ref().set("invalid", true)?;
+        let worlds = self.worlds.borrow();
+        let terrain = worlds.get(&world).and_then(|world| world.chunks.get(&chunk_position)).and_then(|chunk| chunk.tile_layers.get(layer)).and_then(|tile_layer| tile_layer.2.get(&chunk_offset).cloned());
+        terrain
+    }
+    /// Resolves `tileset:terrain`, or a bare terrain name registered by exactly one tileset.
+    pub fn find_terrain(&self, reference: &str) -> mlua::Result<(ImmutableString, ImmutableString)> {
+        if let Some((tileset, terrain)) = reference.split_once(':') {
+            return Ok((tileset.into(), terrain.into()));
+        }
+        let name: ImmutableString = reference.into();
+        let mut tilesets = self.tile_sets.iter().filter(|(_, tileset)| tileset.terrains.contains_key(&name)).map(|(id, _)| id.clone());
+        match (tilesets.next(), tilesets.next()) {
+            (Some(tileset), None) => Ok((tileset, name)),
+            (Some(_), Some(_)) => Err(mlua::Error::runtime(format!("terrain {} is registered by multiple tilesets, use tileset:terrain", reference))),
+            (None, _) => Err(mlua::Error::runtime(format!("terrain {} not found", reference))),
+        }
+    }
+    /// Paints `terrain` (`(tileset, terrain)`) at the position, or clears it, and picks new tiles for it and its terrain neighbours.
+    pub fn set_terrain(&self, tile_pos: TilePosition, world: ImmutableString, layer: ImmutableString, terrain: Option<(ImmutableString, ImmutableString)>) -> mlua::Result<()> {
+        match terrain {
+            Some((tileset_id, name)) => {
+                let tileset = self.tile_sets.get(&tileset_id).ok_or_else(|| mlua::Error::runtime(format!("tileset {} doesn't exist", tileset_id)))?;
+                if !tileset.terrains.contains_key(&name) {
+                    return Err(mlua::Error::runtime(format!("terrain {} not found in tileset {}", name, tileset_id)));
+                }
+                let (chunk_position, chunk_offset) = tile_pos.to_chunk_position();
+                let new_layer = {
+                    let mut chunk = self.get_chunk(chunk_position, world.clone())?;
+                    let new_layer = !chunk.tile_layers.contains_key(&layer);
+                    chunk.tile_layers.entry(layer.clone()).or_insert_with(|| ChunkTileLayer::new()).2.insert(chunk_offset, (tileset_id, name));
+                    new_layer
+                };
+                if new_layer {
+                    self.define_layer(world.clone(), layer.clone(), None)?;
+                }
+            }
+            None => self.set_cell(tile_pos, world.clone(), layer.clone(), TileCell::EMPTY)?,
+        }
+        self.update_terrain_tile(tile_pos, world.clone(), layer.clone())?;
+        for (x, y) in NEIGHBOURS {
+            self.update_terrain_tile(TilePosition { x: tile_pos.x + x, y: tile_pos.y + y }, world.clone(), layer.clone())?;
         }
-        for viewer in chunk.viewers.borrow().values() {
-            let _ = viewer.borrow::<Client>().unwrap().connection.sender.send(MessageS2C::SetTile(tile_pos, tileset_id.to_string(), tile_id));
+        Ok(())
+    }
+    fn update_terrain_tile(&self, tile_pos: TilePosition, world: ImmutableString, layer: ImmutableString) -> mlua::Result<()> {
+        let Some((tileset_id, name)) = self.terrain_at(tile_pos, world.clone(), &layer) else {
+            return Ok(());
+        };
+        //only terrains of the same tileset connect
+        let neighbours = NEIGHBOURS.map(|(x, y)| {
+            self.terrain_at(TilePosition { x: tile_pos.x + x, y: tile_pos.y + y }, world.clone(), &layer)
+                .filter(|(neighbour_tileset, _)| *neighbour_tileset == tileset_id)
+                .map(|(_, neighbour)| neighbour)
+        });
+        let terrain = self.tile_sets.get(&tileset_id).and_then(|tileset| tileset.terrains.get(&name)).unwrap();
+        let tile = terrain.choose(&name, &neighbours).ok_or_else(|| mlua::Error::runtime(format!("terrain {} has no tile for its neighbours", name)))?;
+        let (chunk_position, chunk_offset) = tile_pos.to_chunk_position();
+        let current = self.get_chunk(chunk_position, world.clone())?.tile_layers.get(&layer).map(|tile_layer| tile_layer.0[chunk_offset.index()]);
+        if current == Some(self.tile_cell(&tileset_id, &tile, TileOrientation::default())?) {
+            return Ok(());
         }
+        self.set_tile(tile_pos, world.clone(), layer.clone(), tileset_id.clone(), tile, TileOrientation::default())?;
+        self.get_chunk(chunk_position, world)?.tile_layers.get_mut(&layer).unwrap().2.insert(chunk_offset, (tileset_id, name));
         Ok(())
     }
 }
@@ -337,15 +823,46 @@ pub struct ClientConnection {
     receiver: Receiver<MessageC2S>,
     pub sender: tokio::sync::mpsc::UnboundedSender<MessageS2C>,
 }
+pub struct WorldLayer {
+    name: ImmutableString,
+    z: i32,
+}
+impl WorldLayer {
+    /// Layers are drawn behind entities unless they say otherwise.
+    pub const DEFAULT_Z: i32 = -1;
+}
 pub struct World {
     chunks: HashMap<ChunkPosition, Chunk>,
+    layers: Vec<WorldLayer>,
+    map_objects: Vec<MapObject>,
+    background: WorldBackground,
+    background_images: HashMap<String, Vec<u8>>,
+    properties: LuaOwnedTable,
+    /// Called with the origin of every new chunk before the `load_chunk` event.
+    generator: Option<LuaOwnedFunction>,
 }
 impl World {
-    pub fn new() -> Self {
+    pub fn new(generator: Option<LuaOwnedFunction>, properties: LuaOwnedTable) -> Self {
         World {
-            chunks: HashMap::new()
+            chunks: HashMap::new(),
+            layers: Vec::new(),
+            map_objects: Vec::new(),
+            background: WorldBackground::default(),
+            background_images: HashMap::new(),
+            properties,
+            generator,
         }
     }
+    pub fn background_message(&self) -> MessageS2C {
+        let images = self.background.images.iter().filter_map(|image| Some((image.image.clone(), self.background_images.get(&image.image)?.clone()))).collect();
+        MessageS2C::SetBackground(self.background.clone(), images)
+    }
+    pub fn layers_message(&self) -> MessageS2C {
+        MessageS2C::SetWorldLayers(self.layers.iter().map(|layer| (layer.name.to_string(), layer.z)).collect())
+    }
+    pub fn viewers(&self) -> HashSet<Uuid> {
+        self.chunks.values().flat_map(|chunk| chunk.viewers.borrow().keys().cloned().collect::<Vec<_>>()).collect()
+    }
 }
 pub struct Chunk {
     tile_layers: HashMap<ImmutableString, ChunkTileLayer>,
@@ -360,25 +877,71 @@ impl Chunk {
             viewers: RefCell::new(HashMap::new()),
         }
     }
+    pub fn is_active(&self) -> bool {
+        !self.viewers.borrow().is_empty()
+    }
 }
 pub struct TileType {
     data: LuaOwnedTable,
     id: u32,
     collision_mask: u32,
+    collision_shapes: Vec<TileCollisionShape>,
     asset_position: Option<(u8, u8)>,
+    animation: Vec<TileFrame>,
+    callbacks: HashMap<ImmutableString, LuaOwnedFunction>,
+    /// Keys of the tile's data sent to clients.
+    replicated_data: Vec<ImmutableString>,
+}
+impl TileType {
+    pub const CALLBACKS: [&'static str; 4] = ["on_random_tick", "on_neighbour_changed", "on_scheduled_update", "on_tile_data_changed"];
+    pub fn has_callback(&self, name: &str) -> bool {
+        self.callbacks.contains_key::<ImmutableString>(&name.into())
+    }
+    pub fn call_callback<'lua, T: IntoLuaMulti<'lua>>(&'lua self, name: &str, args: T) -> mlua::Result<()> {
+        if let Some(callback) = self.callbacks.get::<ImmutableString>(&name.into()) {
+            callback.call::<_, ()>(args)?;
+        }
+        Ok(())
+    }
+    pub fn appearance(&self) -> Option<TileAppearance> {
+        if !self.animation.is_empty() {
+            return Some(TileAppearance { frames: self.animation.clone() });
+        }
+        self.asset_position.map(|position| TileAppearance { frames: vec![TileFrame { position, duration: 0. }] })
+    }
 }
 pub struct TileSet {
     tiles: HashMap<ImmutableString, TileType>,
     tile_ids: Vec<ImmutableString>,
+    terrains: HashMap<ImmutableString, Terrain>,
+    index: u16,
     asset: (Vec<u8>, u8),
+    file: String,
+    tiled_name: Option<String>,
 }
 impl TileSet {
-    pub fn new(asset: (Vec<u8>, u8)) -> Self {
+    pub fn new(asset: (Vec<u8>, u8), file: String, tiled_name: Option<String>) -> Self {
         TileSet {
             tiles: HashMap::new(),
             tile_ids: Vec::new(),
+            terrains: HashMap::new(),
+            index: 0,
             asset,
+            file,
+            tiled_name,
+        }
+    }
+    /// Whether this tileset was registered from the given Tiled tileset by name.
+    pub fn matches_tiled_name(&self, tileset: &tiled::Tileset) -> bool {
+        self.tiled_name.as_ref().is_some_and(|tiled_name| *tiled_name == tileset.name)
+    }
+    /// Whether this tileset uses the image of the given Tiled tileset, only checked when no Tiled name was given.
+    pub fn matches_tiled_image(&self, tileset: &tiled::Tileset) -> bool {
+        if self.tiled_name.is_some() {
+            return false;
         }
+        let file_stem = |path: &std::path::Path| path.file_stem().map(|stem| stem.to_os_string());
+        tileset.image.as_ref().is_some_and(|image| file_stem(&image.source) == file_stem(std::path::Path::new(&self.file)))
     }
     pub fn register(&mut self, data: LuaOwnedTable) -> mlua::Result<()> {
         let id: ImmutableString = data.to_ref().get::<&str, String>("id").map_err(|_| mlua::Error::runtime("tile id not specified"))?.into();
@@ -386,21 +949,110 @@ impl TileSet {
             return Err(mlua::Error::runtime("registered two tiles with same id"));
         }
         let num_id = self.tile_ids.len() as u32;
-        let collision_mask: Option<u32> = data.to_ref().get("collision_mask").unwrap();
+        let collision_mask: Option<CollisionMask> = data.to_ref().get("collision_mask")?;
         data.to_ref().set("collision_mask", None::<bool>).unwrap();
+        let collision_shape: Value = data.to_ref().get("collision_shape").unwrap();
+        data.to_ref().set("collision_shape", None::<bool>).unwrap();
+        let mut collision_shapes = TileSet::load_collision_shapes(collision_shape)?;
+        if collision_shapes.is_empty() {
+            collision_shapes.push(TileCollisionShape::full());
+        }
         let asset_pos: Option<Table> = data.to_ref().get("asset_pos").unwrap();
         data.to_ref().set("asset_pos", None::<bool>).unwrap();
+        let animation: Option<Vec<Table>> = data.to_ref().get("animation")?;
+        data.to_ref().set("animation", None::<bool>).unwrap();
+        let replicated_data: Option<Vec<String>> = data.to_ref().get("replicate")?;
+        data.to_ref().set("replicate", None::<bool>).unwrap();
+        let mut callbacks = HashMap::new();
+        for name in TileType::CALLBACKS {
+            if let Some(callback) = data.to_ref().get::<_, Option<LuaOwnedFunction>>(name)? {
+                callbacks.insert(name.into(), callback);
+            }
+            data.to_ref().set(name, None::<bool>).unwrap();
+        }
+        let animation = animation.unwrap_or_default().iter().map(|frame| Ok(TileFrame {
+            position: (frame.get("x")?, frame.get("y")?),
+            duration: frame.get("duration")?,
+        })).collect::<mlua::Result<Vec<_>>>()?;
         self.tile_ids.push(id.clone());
         self.tiles.insert(id, TileType {
             id: num_id,
             asset_position: asset_pos.map(|table| (table.get("x").unwrap(), table.get("y").unwrap())),
-            collision_mask: collision_mask.unwrap_or(0),
+            animation,
+            callbacks,
+            replicated_data: replicated_data.unwrap_or_default().into_iter().map(|key| key.into()).collect(),
+            collision_mask: collision_mask.map(|mask| mask.0).unwrap_or(0),
+            collision_shapes,
             data,
         });
         Ok(())
     }
+    fn load_collision_shapes(value: Value) -> mlua::Result<Vec<TileCollisionShape>> {
+        Ok(match value {
+            Value::Nil => Vec::new(),
+            Value::String(preset) => vec![match preset.to_str()? {
+                "full" => TileCollisionShape::full(),
+                "half_top" => TileCollisionShape::Box(AABB { x: 0., y: 0., w: 1., h: 0.5 }),
+                "half_bottom" => TileCollisionShape::Box(AABB { x: 0., y: 0.5, w: 1., h: 0.5 }),
+                "half_left" => TileCollisionShape::Box(AABB { x: 0., y: 0., w: 0.5, h: 1. }),
+                "half_right" => TileCollisionShape::Box(AABB { x: 0.5, y: 0., w: 0.5, h: 1. }),
+                "platform" => TileCollisionShape::Platform(AABB { x: 0., y: 0., w: 1., h: 0.5 }),
+                "slope_45_up_right" => TileCollisionShape::slope(0., 1., false),
+                "slope_45_up_left" => TileCollisionShape::slope(1., 0., false),
+                "slope_22_up_right_low" => TileCollisionShape::slope(0., 0.5, false),
+                "slope_22_up_right_high" => TileCollisionShape::slope(0.5, 1., false),
+                "slope_22_up_left_low" => TileCollisionShape::slope(0.5, 0., false),
+                "slope_22_up_left_high" => TileCollisionShape::slope(1., 0.5, false),
+                preset => return Err(mlua::Error::runtime(format!("unknown collision shape {}", preset))),
+            }],
+            Value::Table(table) => {
+                let shape_type: Option<String> = table.get("type")?;
+                match shape_type {
+                    Some(shape_type) => {
+                        let aabb = || -> mlua::Result<AABB> {
+                            Ok(AABB {
+                                x: table.get::<_, Option<f64>>("x")?.unwrap_or(0.),
+                                y: table.get::<_, Option<f64>>("y")?.unwrap_or(0.),
+                                w: table.get::<_, Option<f64>>("w")?.unwrap_or(1.),
+                                h: table.get::<_, Option<f64>>("h")?.unwrap_or(1.),
+                            })
+                        };
+                        vec![match shape_type.as_str() {
+                            "box" => TileCollisionShape::Box(aabb()?),
+                            "platform" => TileCollisionShape::Platform(aabb()?),
+                            "slope" => TileCollisionShape::slope(
+                                table.get::<_, Option<f64>>("from")?.unwrap_or(0.),
+                                table.get::<_, Option<f64>>("to")?.unwrap_or(1.),
+                                table.get::<_, Option<bool>>("ceiling")?.unwrap_or(false),
+                            ),
+                            "polygon" => TileCollisionShape::Polygon(table.get::<_, Table>("points")?.sequence_values::<Table>().map(|point| {
+                                let point = point?;
+                                Ok((point.get("x")?, point.get("y")?))
+                            }).collect::<mlua::Result<_>>()?),
+                            shape_type => return Err(mlua::Error::runtime(format!("unknown collision shape type {}", shape_type))),
+                        }]
+                    }
+                    None => {
+                        let mut shapes = Vec::new();
+                        for shape in table.sequence_values::<Value>() {
+                            shapes.extend(TileSet::load_collision_shapes(shape?)?);
+                        }
+                        shapes
+                    }
+                }
+            }
+            _ => return Err(mlua::Error::runtime("collision shape must be a preset name or table")),
+        })
+    }
+    pub fn register_terrain(&mut self, name: ImmutableString, terrain: Terrain) -> mlua::Result<()> {
+        if let Some(missing) = terrain.tile_ids().into_iter().find(|id| !self.tiles.contains_key(*id)) {
+            return Err(mlua::Error::runtime(format!("terrain {} uses unknown tile {}", name, missing)));
+        }
+        self.terrains.insert(name, terrain);
+        Ok(())
+    }
     pub fn by_id(&self, id: u32) -> Option<&TileType> {
-        self.tiles.get(&self.tile_ids[id as usize])
+        self.tiles.get(self.tile_ids.get(id as usize)?)
     }
 }
 pub struct EntityType {
@@ -408,14 +1060,26 @@ pub struct EntityType {
     data: LuaOwnedTable,
     data_metatable: LuaOwnedTable,
     animations: HashMap<ImmutableString, AnimationData>,
+    animation_events: HashMap<ImmutableString, HashMap<usize, Vec<ImmutableString>>>,
     size: (f64, f64),
+    tags: Vec<ImmutableString>,
+    callbacks: HashMap<ImmutableString, LuaOwnedFunction>,
+}
+impl EntityType {
+    pub const CALLBACKS: [&'static str; 6] = ["on_spawn", "on_tick", "on_remove", "on_chunk_change", "on_animation_event", "on_animation_end"];
+    pub fn call_callback<T: for<'a> IntoLuaMulti<'a>>(&self, name: &str, args: T) -> mlua::Result<()> {
+        if let Some(callback) = self.callbacks.get::<ImmutableString>(&name.into()) {
+            callback.call::<_, ()>(args)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct EntityRegistry {
     entities: HashMap<ImmutableString, EntityType>,
 }
 impl EntityRegistry {
-    pub fn register(&mut self, lua: &Lua, id: ImmutableString, data: LuaOwnedTable) {
+    pub fn register(&mut self, lua: &Lua, id: ImmutableString, data: LuaOwnedTable, sprite_sheets: &mut HashMap<ImmutableString, Vec<u8>>) -> mlua::Result<()> {
         let colliders: Table = data.to_ref().get("colliders").unwrap();
         data.to_ref().set("colliders", None::<bool>).unwrap();
         let width: f64 = data.to_ref().get("width").unwrap();
@@ -424,35 +1088,248 @@ impl EntityRegistry {
         data.to_ref().set("height", None::<bool>).unwrap();
         let animations: Table = data.to_ref().get("animations").unwrap();
         data.to_ref().set("animations", None::<bool>).unwrap();
+        let tags: Option<Vec<String>> = data.to_ref().get("tags").unwrap();
+        data.to_ref().set("tags", None::<bool>).unwrap();
+        let mut callbacks = HashMap::new();
+        for name in EntityType::CALLBACKS {
+            if let Some(callback) = data.to_ref().get::<_, Option<LuaOwnedFunction>>(name).unwrap() {
+                callbacks.insert(name.into(), callback);
+            }
+            data.to_ref().set(name, None::<bool>).unwrap();
+        }
+        let mut animation_events = HashMap::new();
+        let animations = animations.pairs::<String, Table>().map(|animation| {
+            let (name, animation) = animation?;
+            let events = EntityRegistry::load_animation_events(&animation).map_err(|error| mlua::Error::runtime(format!("animation {}: {}", name, error)))?;
+            let animation = EntityRegistry::load_animation(&animation, sprite_sheets).map_err(|error| mlua::Error::runtime(format!("animation {}: {}", name, error)))?;
+            if !events.is_empty() {
+                animation_events.insert(name.as_str().into(), events);
+            }
+            Ok((name.into(), animation))
+        }).collect::<mlua::Result<_>>()?;
         let data_metatable = lua.create_table().unwrap().into_owned();
         data_metatable.to_ref().set("__index", data.clone()).unwrap();
+        let colliders = colliders.pairs::<String, Table>().map(|collider| {
+            let (name, collider) = collider?;
+            let mask = match collider.get::<_, Option<CollisionMask>>("layer")? {
+                Some(mask) => Some(mask),
+                None => collider.get::<_, Option<CollisionMask>>("mask")?,
+            };
+            Ok((name.into(), Collider {
+                aabb: AABB { x: collider.get("x")?, y: collider.get("y")?, w: collider.get("w")?, h: collider.get("h")? },
+                mask: mask.map(|mask| mask.0).unwrap_or(0),
+                collides_with: collider.get::<_, Option<CollisionMask>>("collides_with")?.map(|mask| mask.0),
+            }))
+        }).collect::<mlua::Result<_>>()?;
         self.entities.insert(id, EntityType {
-            colliders: colliders.pairs::<String, Table>().filter_map(|collider| match collider {
-                Ok((name, collider)) => Some((name.into(), Collider {
-                    aabb: AABB { x: collider.get("x").unwrap(), y: collider.get("y").unwrap(), w: collider.get("w").unwrap(), h: collider.get("h").unwrap() },
-                    mask: collider.get("mask").unwrap(),
-                })),
-                Err(_) => None,
-            }).collect(),
-            animations: animations.pairs::<String, Table>().filter_map(|animation| match animation {
-                Ok((name, animation)) => Some((name.into(), AnimationData {
-                    flip: animation.get::<_, Option<bool>>("flip").unwrap().unwrap_or(false),
-                    count: animation.get("count").unwrap(),
-                    looped: animation.get("loop").unwrap(),
-                    period: animation.get::<_, Option<f64>>("period").unwrap().unwrap_or(0.),
-                    image: std::fs::read(format!("assets/{}.png", animation.get::<_, String>("file").unwrap())).unwrap(),
-                })),
-                Err(_) => None
-            }).collect(),
+            colliders,
+            animations,
+            animation_events,
             size: (width, height),
+            tags: tags.unwrap_or_default().into_iter().map(|tag| tag.into()).collect(),
+            callbacks,
             data_metatable,
             data,
         });
+        Ok(())
+    }
+    fn load_sprite_sheet(sprite_sheets: &mut HashMap<ImmutableString, Vec<u8>>, name: &str) -> mlua::Result<(u32, u32)> {
+        let key: ImmutableString = name.into();
+        if !sprite_sheets.contains_key(&key) {
+            let path = format!("assets/{}.png", name);
+            let image = std::fs::read(&path).map_err(|error| mlua::Error::runtime(format!("couldn't read {}: {}", path, error)))?;
+            sprite_sheets.insert(key.clone(), image);
+        }
+        png_size(sprite_sheets.get(&key).unwrap()).ok_or_else(|| mlua::Error::runtime(format!("sprite sheet {} isn't a png image", name)))
+    }
+    /// Reads event markers keyed by frame number, either from `events = {[3] = "footstep"}`
+    /// or from `event`/`events` fields of entries in `frames`.
+    fn load_animation_events(animation: &Table) -> mlua::Result<HashMap<usize, Vec<ImmutableString>>> {
+        fn names(value: Value) -> mlua::Result<Vec<ImmutableString>> {
+            match value {
+                Value::Nil => Ok(Vec::new()),
+                Value::String(name) => Ok(vec![name.to_str()?.into()]),
+                Value::Table(names) => names.sequence_values::<String>().map(|name| Ok(name?.into())).collect(),
+                _ => Err(mlua::Error::runtime("animation event must be a name or list of names")),
+            }
+        }
+        let mut events: HashMap<usize, Vec<ImmutableString>> = HashMap::new();
+        if let Some(table) = animation.get::<_, Option<Table>>("events")? {
+            for pair in table.pairs::<usize, Value>() {
+                let (frame, value) = pair?;
+                if frame == 0 {
+                    return Err(mlua::Error::runtime("animation event frames start at 1"));
+                }
+                events.entry(frame - 1).or_default().extend(names(value)?);
+            }
+        }
+        if let Some(frames) = animation.get::<_, Option<Vec<Value>>>("frames")? {
+            for (index, frame) in frames.into_iter().enumerate() {
+                if let Value::Table(frame) = frame {
+                    for key in ["event", "events"] {
+                        events.entry(index).or_default().extend(names(frame.get(key)?)?);
+                    }
+                }
+            }
+        }
+        events.retain(|_, names| !names.is_empty());
+        Ok(events)
+    }
+    /// Accepts an Aseprite export (`aseprite`, `tag`), a frame list on a sprite sheet (`sheet`, `frames`)
+    /// or a horizontal strip (`file`/`sheet`, `row`, `column`, `count`).
+    fn load_animation(animation: &Table, sprite_sheets: &mut HashMap<ImmutableString, Vec<u8>>) -> mlua::Result<AnimationData> {
+        let looped = animation.get::<_, Option<bool>>("loop")?.unwrap_or(true);
+        let flip = animation.get::<_, Option<bool>>("flip")?.unwrap_or(false);
+        if let Some(file) = animation.get::<_, Option<String>>("aseprite")? {
+            let mut animation_data = AsepriteSheet::load(file.as_str())?.animation(animation.get::<_, Option<String>>("tag")?.as_deref(), looped, flip)?;
+            EntityRegistry::load_sprite_sheet(sprite_sheets, animation_data.sheet.as_str())?;
+            if let Some(ping_pong) = animation.get::<_, Option<bool>>("ping_pong")? {
+                animation_data.ping_pong = ping_pong;
+            }
+            return Ok(animation_data);
+        }
+        let sheet = match animation.get::<_, Option<String>>("sheet")? {
+            Some(sheet) => sheet,
+            None => animation.get::<_, Option<String>>("file")?.ok_or_else(|| mlua::Error::runtime("animation needs a sheet, file or aseprite export"))?,
+        };
+        let (sheet_width, sheet_height) = EntityRegistry::load_sprite_sheet(sprite_sheets, sheet.as_str())?;
+        let count = animation.get::<_, Option<u16>>("count")?;
+        let frame_width = animation.get::<_, Option<u16>>("frame_width")?.unwrap_or((sheet_width / count.unwrap_or(1).max(1) as u32) as u16);
+        let frame_height = animation.get::<_, Option<u16>>("frame_height")?.unwrap_or(sheet_height as u16);
+        let period = animation.get::<_, Option<f64>>("period")?.unwrap_or(0.);
+        let durations = animation.get::<_, Option<Vec<f64>>>("durations")?.unwrap_or_default();
+        let duration = |index: usize, frame: Option<&Table>| -> mlua::Result<f64> {
+            Ok(match frame.map(|frame| frame.get::<_, Option<f64>>("duration")).transpose()?.flatten() {
+                Some(duration) => duration,
+                None => durations.get(index).copied().unwrap_or(period),
+            })
+        };
+        let frames = match animation.get::<_, Option<Vec<Table>>>("frames")? {
+            Some(frames) => frames.iter().enumerate().map(|(index, frame)| {
+                Ok(match frame.get::<_, Option<u16>>("w")? {
+                    Some(w) => AnimationFrame {
+                        x: frame.get("x")?,
+                        y: frame.get("y")?,
+                        w,
+                        h: frame.get::<_, Option<u16>>("h")?.unwrap_or(frame_height),
+                        duration: duration(index, Some(frame))?,
+                    },
+                    None => AnimationFrame {
+                        x: frame.get::<_, Option<u16>>("column")?.unwrap_or(0) * frame_width,
+                        y: frame.get::<_, Option<u16>>("row")?.unwrap_or(0) * frame_height,
+                        w: frame_width,
+                        h: frame_height,
+                        duration: duration(index, Some(frame))?,
+                    },
+                })
+            }).collect::<mlua::Result<Vec<_>>>()?,
+            None => {
+                let row = animation.get::<_, Option<u16>>("row")?.unwrap_or(0);
+                let column = animation.get::<_, Option<u16>>("column")?.unwrap_or(0);
+                (0..count.unwrap_or(1)).map(|index| Ok(AnimationFrame {
+                    x: (column + index) * frame_width,
+                    y: row * frame_height,
+                    w: frame_width,
+                    h: frame_height,
+                    duration: duration(index as usize, None)?,
+                })).collect::<mlua::Result<Vec<_>>>()?
+            }
+        };
+        if frames.is_empty() {
+            return Err(mlua::Error::runtime("animation has no frames"));
+        }
+        Ok(AnimationData {
+            sheet,
+            frames,
+            looped,
+            ping_pong: animation.get::<_, Option<bool>>("ping_pong")?.unwrap_or(false),
+            flip,
+        })
     }
 }
-pub struct ChunkTileLayer(Vec<u32>, HashMap<ChunkOffset, LuaOwnedTable>);
+pub struct CollisionLayers {
+    layers: Vec<ImmutableString>,
+    matrix: HashMap<u32, u32>,
+}
+impl CollisionLayers {
+    pub fn new() -> Self {
+        CollisionLayers {
+            layers: Vec::new(),
+            matrix: HashMap::new(),
+        }
+    }
+    pub fn register(&mut self, name: ImmutableString) -> mlua::Result<u32> {
+        if let Some(layer) = self.get(&name) {
+            return Ok(layer);
+        }
+        if self.layers.len() >= u32::BITS as usize {
+            return Err(mlua::Error::runtime("too many collision layers"));
+        }
+        self.layers.push(name);
+        Ok(1 << (self.layers.len() - 1))
+    }
+    pub fn get(&self, name: &ImmutableString) -> Option<u32> {
+        self.layers.iter().position(|layer| layer == name).map(|index| 1 << index)
+    }
+    pub fn resolve(&self, value: Value) -> mlua::Result<u32> {
+        match value {
+            Value::Integer(mask) => Ok(mask as u32),
+            Value::Number(mask) => Ok(mask as u32),
+            Value::String(name) => {
+                let name = name.to_str()?;
+                self.get(&name.into()).ok_or_else(|| mlua::Error::runtime(format!("collision layer {} doesn't exist", name)))
+            }
+            Value::Table(layers) => {
+                let mut mask = 0;
+                for layer in layers.sequence_values::<Value>() {
+                    mask |= self.resolve(layer?)?;
+                }
+                Ok(mask)
+            }
+            _ => Err(mlua::Error::runtime("collision mask must be a number, layer name or list of layer names")),
+        }
+    }
+    pub fn set_collides(&mut self, first: u32, second: u32, collides: bool) {
+        for (from, to) in [(first, second), (second, first)] {
+            for bit in 0..u32::BITS {
+                let layer = 1 << bit;
+                if from & layer != 0 {
+                    let row = self.matrix.entry(layer).or_insert(layer);
+                    if collides {
+                        *row |= to;
+                    } else {
+                        *row &= !to;
+                    }
+                }
+            }
+        }
+    }
+    pub fn collides_with(&self, mask: u32) -> u32 {
+        (0..u32::BITS).map(|bit| 1 << bit).filter(|layer| mask & layer != 0).fold(0, |collides_with, layer| {
+            collides_with | self.matrix.get(&layer).copied().unwrap_or(layer)
+        })
+    }
+}
+/// Cells, tile data tables and the `(tileset, terrain)` of cells placed by `set_terrain`.
+pub struct ChunkTileLayer(Vec<TileCell>, HashMap<ChunkOffset, TileData>, HashMap<ChunkOffset, (ImmutableString, ImmutableString)>);
 impl ChunkTileLayer {
     pub fn new() -> Self {
-        ChunkTileLayer(vec![0; (CHUNK_SIZE * CHUNK_SIZE) as usize], HashMap::new())
+        ChunkTileLayer(vec![TileCell::default(); (CHUNK_SIZE * CHUNK_SIZE) as usize], HashMap::new(), HashMap::new())
+    }
+    /// `SetTileData` for the replicated keys of every tile in the layer that has data.
+    pub fn replicated_data_messages(&self, server: &Server, chunk_position: ChunkPosition, layer: &ImmutableString) -> Vec<MessageS2C> {
+        let mut messages = Vec::new();
+        for (chunk_offset, tile_data) in &self.1 {
+            let Some(tile_type) = server.tile_type(self.0[chunk_offset.index()]) else {
+                continue;
+            };
+            for key in &tile_type.replicated_data {
+                let value: Value = tile_data.values.to_ref().raw_get(key.to_string()).unwrap();
+                if let Ok(Some(value)) = tile_data_value_from_lua(&value) {
+                    messages.push(MessageS2C::SetTileData(TilePosition::from_chunk_position(chunk_position, *chunk_offset), layer.to_string(), key.to_string(), Some(value)));
+                }
+            }
+        }
+        messages
     }
 }
\ No newline at end of file
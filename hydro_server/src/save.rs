@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use bincode::config;
+use immutable_string::ImmutableString;
+use mlua::{Lua, Value};
+use serde::{Deserialize, Serialize};
+
+use hydro_common::{TileCell, TileDataValue, TileOrientation};
+use hydro_common::pos::{ChunkOffset, ChunkPosition, TilePosition};
+
+use crate::{ChunkTileLayer, Server};
+use crate::lua::{parse_tile_reference, tile_data_value_from_lua, tile_data_value_to_lua, TileData};
+
+/// Tiles of a world as written by `save_world`. Tiles are kept as `tileset:tile` references,
+/// so saves stay valid when tilesets are added or reordered.
+#[derive(Serialize, Deserialize)]
+pub struct WorldSave {
+    layers: Vec<(String, i32)>,
+    chunks: Vec<ChunkSave>,
+}
+#[derive(Serialize, Deserialize)]
+struct ChunkSave {
+    position: ChunkPosition,
+    layers: Vec<LayerSave>,
+}
+#[derive(Serialize, Deserialize)]
+struct LayerSave {
+    name: String,
+    palette: Vec<String>,
+    /// Index into `palette` plus one, zero for empty cells, and the orientation of every cell.
+    cells: Vec<(u16, u8)>,
+    data: Vec<(u16, Vec<(TileDataValue, TileDataValue)>)>,
+    terrains: Vec<(u16, String, String)>,
+}
+fn save_path(world: &ImmutableString) -> String {
+    format!("saves/{}.bin", world)
+}
+pub fn save_world(server: &Server, world_name: &ImmutableString) -> mlua::Result<()> {
+    let save = {
+        let worlds = server.worlds.borrow();
+        let world = worlds.get(world_name).ok_or_else(|| mlua::Error::runtime(format!("world {} doesn't exist", world_name)))?;
+        WorldSave {
+            layers: world.layers.iter().map(|layer| (layer.name.to_string(), layer.z)).collect(),
+            chunks: world.chunks.iter().filter(|(_, chunk)| !chunk.tile_layers.is_empty()).map(|(position, chunk)| Ok(ChunkSave {
+                position: *position,
+                layers: chunk.tile_layers.iter().map(|(name, tile_layer)| save_layer(server, name, tile_layer)).collect::<mlua::Result<Vec<_>>>()?,
+            })).collect::<mlua::Result<Vec<_>>>()?,
+        }
+    };
+    let path = save_path(world_name);
+    let data = bincode::serde::encode_to_vec(&save, config::standard()).map_err(|error| mlua::Error::runtime(format!("couldn't encode {}: {}", path, error)))?;
+    std::fs::create_dir_all("saves").and_then(|_| std::fs::write(&path, data)).map_err(|error| mlua::Error::runtime(format!("couldn't write {}: {}", path, error)))
+}
+fn save_layer(server: &Server, name: &ImmutableString, tile_layer: &ChunkTileLayer) -> mlua::Result<LayerSave> {
+    let (palette, cells) = encode_cells(&tile_layer.0, |cell| server.tile_reference(cell));
+    let data = tile_layer.1.iter().map(|(chunk_offset, tile_data)| {
+        let Some(TileDataValue::Table(pairs)) = tile_data_value_from_lua(&Value::Table(tile_data.values.to_ref()))? else {
+            unreachable!();
+        };
+        Ok((chunk_offset.index() as u16, pairs))
+    }).collect::<mlua::Result<Vec<_>>>()?;
+    Ok(LayerSave {
+        name: name.to_string(),
+        palette,
+        cells,
+        data,
+        terrains: tile_layer.2.iter().map(|(chunk_offset, (tileset, terrain))| (chunk_offset.index() as u16, tileset.to_string(), terrain.to_string())).collect(),
+    })
+}
+/// Palette of the tile references in `cells` and the cells as `LayerSave::cells`,
+/// cells without a reference are saved empty.
+fn encode_cells(cells: &[TileCell], reference: impl Fn(TileCell) -> Option<String>) -> (Vec<String>, Vec<(u16, u8)>) {
+    let mut palette = Vec::new();
+    let mut palette_indices: HashMap<(u16, u32), u16> = HashMap::new();
+    let cells = cells.iter().map(|cell| {
+        let Some(reference) = reference(*cell) else {
+            return (0, 0);
+        };
+        let index = *palette_indices.entry((cell.tileset, cell.id)).or_insert_with(|| {
+            palette.push(reference);
+            palette.len() as u16
+        });
+        (index, cell.orientation.0)
+    }).collect();
+    (palette, cells)
+}
+/// Inverse of `encode_cells`, `resolve` finds the cell of a palette reference.
+fn decode_cells(palette: &[String], cells: &[(u16, u8)], resolve: impl Fn(&ImmutableString, &ImmutableString) -> mlua::Result<TileCell>, corrupt: impl Fn() -> mlua::Error) -> mlua::Result<Vec<TileCell>> {
+    let palette = palette.iter().map(|reference| {
+        let (tileset, tile) = parse_tile_reference(reference)?;
+        resolve(&tileset, &tile)
+    }).collect::<mlua::Result<Vec<_>>>()?;
+    cells.iter().map(|(tile, orientation)| Ok(match tile {
+        0 => TileCell::EMPTY,
+        tile => TileCell { orientation: TileOrientation(*orientation), ..*palette.get(*tile as usize - 1).ok_or_else(&corrupt)? },
+    })).collect()
+}
+/// Places the tiles of the world's save into it, returns false when there is no save.
+/// Restored tiles don't trigger `on_neighbour_changed`.
+pub fn load_world(server: &Server, lua: &Lua, world: &ImmutableString) -> mlua::Result<bool> {
+    let path = save_path(world);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(mlua::Error::runtime(format!("couldn't read {}: {}", path, error))),
+    };
+    let (save, _): (WorldSave, usize) = bincode::serde::decode_from_slice(&data, config::standard()).map_err(|error| mlua::Error::runtime(format!("couldn't decode {}: {}", path, error)))?;
+    let corrupt = || mlua::Error::runtime(format!("save {} is corrupt", path));
+    let mut restored_data = Vec::new();
+    for (name, z) in save.layers {
+        server.define_layer(world.clone(), name.into(), Some(z))?;
+    }
+    for chunk in save.chunks {
+        for layer in chunk.layers {
+            let layer_name: ImmutableString = layer.name.as_str().into();
+            let cells = decode_cells(&layer.palette, &layer.cells, |tileset, tile| server.tile_cell(tileset, tile, TileOrientation::default()), corrupt)?
+                .into_iter().enumerate()
+                .map(|(index, cell)| (TilePosition::from_chunk_position(chunk.position, ChunkOffset::from_index(index)), cell))
+                .collect::<Vec<_>>();
+            server.write_cells(world.clone(), layer_name.clone(), cells, false)?;
+
+            let mut chunk_data = server.get_chunk(chunk.position, world.clone())?;
+            let Some(tile_layer) = chunk_data.tile_layers.get_mut(&layer_name) else {
+                continue;
+            };
+            if !layer.data.is_empty() {
+                restored_data.push((chunk.position, layer_name.clone()));
+            }
+            for (index, pairs) in layer.data {
+                let chunk_offset = ChunkOffset::from_index(index as usize);
+                let cell = *tile_layer.0.get(index as usize).ok_or_else(corrupt)?;
+                let Some(tile_type) = server.tile_type(cell) else {
+                    continue;
+                };
+                let Value::Table(values) = tile_data_value_to_lua(lua, &TileDataValue::Table(pairs))? else {
+                    unreachable!();
+                };
+                let position = TilePosition::from_chunk_position(chunk.position, chunk_offset);
+                tile_layer.1.insert(chunk_offset, TileData::new(lua, tile_type.data.clone(), world.clone(), layer_name.clone(), position, values)?);
+            }
+            for (index, tileset, terrain) in layer.terrains {
+                tile_layer.2.insert(ChunkOffset::from_index(index as usize), (tileset.into(), terrain.into()));
+            }
+        }
+    }
+    //tile changes clear tile data on clients, so they have to arrive before the restored data
+    server.send_tile_updates();
+    for (chunk_position, layer_name) in restored_data {
+        let chunk_data = server.get_chunk(chunk_position, world.clone())?;
+        let Some(tile_layer) = chunk_data.tile_layers.get(&layer_name) else {
+            continue;
+        };
+        for viewer in chunk_data.viewers.borrow().keys() {
+            for message in tile_layer.replicated_data_messages(server, chunk_position, &layer_name) {
+                server.try_send_message_to(*viewer, message);
+            }
+        }
+    }
+    Ok(true)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILESETS: [&str; 2] = ["ground", "walls"];
+    const TILES: [&str; 3] = ["grass", "stone", "water"];
+
+    fn reference(cell: TileCell) -> Option<String> {
+        Some(format!("{}:{}", TILESETS.get(cell.tileset as usize)?, TILES.get(cell.id as usize)?))
+    }
+    fn resolve(tileset: &ImmutableString, tile: &ImmutableString) -> mlua::Result<TileCell> {
+        let index = |names: &[&str], name: &ImmutableString| names.iter().position(|candidate| *candidate == name.as_ref()).ok_or_else(|| mlua::Error::runtime(format!("{} doesn't exist", name)));
+        Ok(TileCell { tileset: index(&TILESETS, tileset)? as u16, id: index(&TILES, tile)? as u32, orientation: TileOrientation::default() })
+    }
+    fn corrupt() -> mlua::Error {
+        mlua::Error::runtime("corrupt")
+    }
+    fn cell(tileset: u16, id: u32, orientation: u8) -> TileCell {
+        TileCell { tileset, id, orientation: TileOrientation(orientation) }
+    }
+
+    #[test]
+    fn palette_round_trip_keeps_tiles_and_orientations() {
+        let cells = [cell(0, 0, 0), TileCell::EMPTY, cell(1, 2, 5), cell(0, 0, 3), cell(0, 1, 0)];
+        let (palette, encoded) = encode_cells(&cells, reference);
+        assert_eq!(palette, ["ground:grass", "walls:water", "ground:stone"]);
+        assert_eq!(encoded, [(1, 0), (0, 0), (2, 5), (1, 3), (3, 0)]);
+        assert!(decode_cells(&palette, &encoded, resolve, corrupt).unwrap() == cells);
+    }
+    #[test]
+    fn palette_follows_reordered_tilesets() {
+        let (palette, encoded) = encode_cells(&[cell(1, 1, 2)], reference);
+        let reordered = |tileset: &ImmutableString, tile: &ImmutableString| resolve(tileset, tile).map(|cell| TileCell { tileset: 1 - cell.tileset, ..cell });
+        assert!(decode_cells(&palette, &encoded, reordered, corrupt).unwrap() == [cell(0, 1, 2)]);
+    }
+    #[test]
+    fn unknown_cells_are_saved_empty() {
+        let (palette, encoded) = encode_cells(&[cell(7, 0, 1), cell(0, 9, 0)], reference);
+        assert!(palette.is_empty());
+        assert!(decode_cells(&palette, &encoded, resolve, corrupt).unwrap() == [TileCell::EMPTY, TileCell::EMPTY]);
+    }
+    #[test]
+    fn out_of_range_palette_indices_are_corrupt() {
+        assert!(decode_cells(&["ground:grass".to_string()], &[(2, 0)], resolve, corrupt).is_err());
+        assert!(decode_cells(&["grass".to_string()], &[(1, 0)], resolve, corrupt).is_err());
+    }
+}
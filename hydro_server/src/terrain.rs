@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use immutable_string::ImmutableString;
+use mlua::Table;
+
+/// Offsets of the neighbours in Tiled's wang id order: top, top right, right, bottom right, bottom, bottom left, left, top left.
+pub const NEIGHBOURS: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum WangSetType {
+    Corner,
+    Edge,
+    Mixed,
+}
+impl WangSetType {
+    fn uses_slot(&self, slot: usize) -> bool {
+        match self {
+            WangSetType::Corner => slot % 2 == 1,
+            WangSetType::Edge => slot % 2 == 0,
+            WangSetType::Mixed => true,
+        }
+    }
+}
+pub enum TerrainRule {
+    /// 47 tile blob set, keyed by the 8 bit mask of matching neighbours, corners only count between two matching edges.
+    Blob47(HashMap<u8, ImmutableString>),
+    /// 16 tile set, keyed by the 4 bit mask of the tile's top right, bottom right, bottom left and top left corners.
+    /// A corner is set when all four cells sharing it have this terrain, like the corners of a Tiled corner set.
+    MarchingSquares(HashMap<u8, ImmutableString>),
+    /// Color of a Tiled wang set, `colors` holds the terrain names of the set's colors starting from 1.
+    Wang {
+        set_type: WangSetType,
+        color: u8,
+        colors: Vec<ImmutableString>,
+        tiles: Vec<([u8; 8], ImmutableString)>,
+    },
+}
+pub struct Terrain {
+    rule: TerrainRule,
+    default: Option<ImmutableString>,
+}
+impl Terrain {
+    pub fn from_lua(name: &str, table: Table) -> mlua::Result<Self> {
+        let invalid = |what: &str| mlua::Error::runtime(format!("terrain {}: {}", name, what));
+        let kind: String = table.get("type").map_err(|_| invalid("type not specified"))?;
+        let masked_tiles = |max: u8| -> mlua::Result<HashMap<u8, ImmutableString>> {
+            let tiles: Table = table.get("tiles").map_err(|_| invalid("tiles not specified"))?;
+            tiles.pairs::<u8, String>().map(|pair| {
+                let (mask, tile) = pair.map_err(|_| invalid("tiles must map neighbour masks to tile ids"))?;
+                if mask > max {
+                    return Err(invalid(&format!("mask {} is out of range", mask)));
+                }
+                Ok((mask, tile.into()))
+            }).collect()
+        };
+        let rule = match kind.as_str() {
+            "blob47" => TerrainRule::Blob47(masked_tiles(u8::MAX)?),
+            "marching_squares" => TerrainRule::MarchingSquares(masked_tiles(15)?),
+            "wang" => {
+                let set_type = match table.get::<_, Option<String>>("set_type")?.as_deref() {
+                    Some("corner") => WangSetType::Corner,
+                    Some("edge") => WangSetType::Edge,
+                    Some("mixed") | None => WangSetType::Mixed,
+                    Some(other) => return Err(invalid(&format!("unknown wang set type {}", other))),
+                };
+                let colors: Vec<String> = table.get("colors").map_err(|_| invalid("colors not specified"))?;
+                let tiles: Vec<Table> = table.get("tiles").map_err(|_| invalid("tiles not specified"))?;
+                TerrainRule::Wang {
+                    set_type,
+                    color: table.get("color").map_err(|_| invalid("color not specified"))?,
+                    colors: colors.into_iter().map(|color| color.into()).collect(),
+                    tiles: tiles.into_iter().map(|tile| {
+                        let wang_id: Vec<u8> = tile.get("wang_id")?;
+                        let wang_id: [u8; 8] = wang_id.try_into().map_err(|_| invalid("wang_id must have 8 colors"))?;
+                        Ok((wang_id, tile.get::<_, String>("id")?.into()))
+                    }).collect::<mlua::Result<Vec<_>>>()?,
+                }
+            }
+            other => return Err(invalid(&format!("unknown terrain type {}", other))),
+        };
+        Ok(Terrain {
+            rule,
+            default: table.get::<_, Option<String>>("default")?.map(|tile| tile.into()),
+        })
+    }
+    /// Exports every color of a Tiled wang set as a terrain table for `register_tileset`.
+    pub fn wang_set_to_lua<'lua>(lua: &'lua mlua::Lua, terrains: &Table<'lua>, wang_set: &tiled::WangSet) -> mlua::Result<()> {
+        let set_type = match wang_set.wang_set_type {
+            tiled::WangSetType::Corner => "corner",
+            tiled::WangSetType::Edge => "edge",
+            tiled::WangSetType::Mixed => "mixed",
+        };
+        let colors: Vec<&str> = wang_set.wang_colors.iter().map(|color| color.name.as_str()).collect();
+        let mut wang_tiles: Vec<_> = wang_set.wang_tiles.iter().collect();
+        wang_tiles.sort_by_key(|(id, _)| **id);
+        let tiles = lua.create_table()?;
+        for (id, wang_tile) in wang_tiles {
+            let tile = lua.create_table()?;
+            tile.set("id", format!("{}", id))?;
+            tile.set("wang_id", wang_tile.wang_id.0.to_vec())?;
+            tiles.push(tile)?;
+        }
+        for (index, color) in wang_set.wang_colors.iter().enumerate() {
+            let terrain = lua.create_table()?;
+            terrain.set("type", "wang")?;
+            terrain.set("set_type", set_type)?;
+            terrain.set("color", index + 1)?;
+            terrain.set("colors", colors.clone())?;
+            terrain.set("tiles", tiles.clone())?;
+            terrains.set(color.name.as_str(), terrain)?;
+        }
+        Ok(())
+    }
+    pub fn tile_ids(&self) -> Vec<&ImmutableString> {
+        let mut ids: Vec<_> = match &self.rule {
+            TerrainRule::Blob47(tiles) | TerrainRule::MarchingSquares(tiles) => tiles.values().collect(),
+            TerrainRule::Wang { tiles, .. } => tiles.iter().map(|(_, tile)| tile).collect(),
+        };
+        ids.extend(self.default.iter());
+        ids
+    }
+    /// Picks the tile for a cell of terrain `name`, `neighbours` holds the terrains of the surrounding cells in `NEIGHBOURS` order.
+    pub fn choose(&self, name: &ImmutableString, neighbours: &[Option<ImmutableString>; 8]) -> Option<ImmutableString> {
+        let matches = neighbours.each_ref().map(|neighbour| neighbour.as_ref() == Some(name));
+        let mask = (0..8).filter(|slot| matches[*slot]).fold(0u8, |mask, slot| mask | 1 << slot);
+        let tile = match &self.rule {
+            TerrainRule::Blob47(tiles) => {
+                let mut mask = mask;
+                for corner in [1, 3, 5, 7] {
+                    if !matches[corner - 1] || !matches[(corner + 1) % 8] {
+                        mask &= !(1 << corner);
+                    }
+                }
+                tiles.get(&mask)
+            }
+            TerrainRule::MarchingSquares(tiles) => {
+                let mask = (0..4).filter(|corner| [corner * 2, corner * 2 + 1, (corner * 2 + 2) % 8].iter().all(|slot| matches[*slot])).fold(0u8, |mask, corner| mask | 1 << corner);
+                tiles.get(&mask)
+            }
+            TerrainRule::Wang { set_type, color, colors, tiles } => {
+                let color_of = |slot: usize| match &neighbours[slot] {
+                    Some(neighbour) if neighbour == name => *color,
+                    Some(neighbour) => colors.iter().position(|color| color == neighbour).map(|index| index as u8 + 1).unwrap_or(0),
+                    None => 0,
+                };
+                //a corner keeps this terrain only when both edges and the diagonal around it do
+                let wanted: [u8; 8] = std::array::from_fn(|slot| {
+                    if slot % 2 == 0 {
+                        return color_of(slot);
+                    }
+                    let around = [slot - 1, slot, (slot + 1) % 8];
+                    if around.iter().all(|slot| matches[*slot]) {
+                        *color
+                    } else {
+                        around.iter().map(|slot| color_of(*slot)).find(|neighbour| neighbour != color).unwrap_or(0)
+                    }
+                });
+                //ties go to the tile listed first
+                tiles.iter().rev()
+                    .filter(|(wang_id, _)| wang_id.contains(color))
+                    .max_by_key(|(wang_id, _)| (0..8).filter(|slot| set_type.uses_slot(*slot) && wang_id[*slot] == wanted[*slot]).count())
+                    .map(|(_, tile)| tile)
+            }
+        };
+        tile.or(self.default.as_ref()).cloned()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Terrain whose tiles are named after every mask up to `max`, so `choose` returns the mask it computed.
+    fn masked_terrain(rule: fn(HashMap<u8, ImmutableString>) -> TerrainRule, max: u8) -> Terrain {
+        Terrain {
+            rule: rule((0..=max).map(|mask| (mask, mask.to_string().into())).collect()),
+            default: None,
+        }
+    }
+    /// Neighbours in `NEIGHBOURS` order, the slots in `matching` have the terrain `grass`, the others `water`.
+    fn neighbours(matching: &[usize]) -> [Option<ImmutableString>; 8] {
+        std::array::from_fn(|slot| Some(if matching.contains(&slot) { "grass" } else { "water" }.into()))
+    }
+    fn choose(terrain: &Terrain, matching: &[usize]) -> Option<String> {
+        terrain.choose(&"grass".into(), &neighbours(matching)).map(|tile| tile.to_string())
+    }
+
+    #[test]
+    fn blob47_keeps_corners_between_matching_edges() {
+        let terrain = masked_terrain(TerrainRule::Blob47, u8::MAX);
+        assert_eq!(choose(&terrain, &[0, 1, 2, 3, 4, 5, 6, 7]).as_deref(), Some("255"));
+        assert_eq!(choose(&terrain, &[]).as_deref(), Some("0"));
+        //top, top right and right
+        assert_eq!(choose(&terrain, &[0, 1, 2]).as_deref(), Some("7"));
+        //the top right corner alone doesn't count without both edges next to it
+        assert_eq!(choose(&terrain, &[0, 1]).as_deref(), Some("1"));
+        assert_eq!(choose(&terrain, &[1, 3, 5, 7]).as_deref(), Some("0"));
+    }
+    #[test]
+    fn marching_squares_sets_corners_shared_by_four_cells() {
+        let terrain = masked_terrain(TerrainRule::MarchingSquares, 15);
+        assert_eq!(choose(&terrain, &[0, 1, 2, 3, 4, 5, 6, 7]).as_deref(), Some("15"));
+        //right, bottom right and bottom close only the bottom right corner
+        assert_eq!(choose(&terrain, &[2, 3, 4]).as_deref(), Some("2"));
+        //top left corner from left, top left and top
+        assert_eq!(choose(&terrain, &[6, 7, 0]).as_deref(), Some("8"));
+        //edges without the diagonal between them leave every corner open
+        assert_eq!(choose(&terrain, &[0, 2, 4, 6]).as_deref(), Some("0"));
+        assert_eq!(choose(&terrain, &[0, 1, 2, 3, 4]).as_deref(), Some("3"));
+    }
+    #[test]
+    fn missing_masks_fall_back_to_the_default_tile() {
+        let mut terrain = Terrain {
+            rule: TerrainRule::MarchingSquares(HashMap::from([(15, "full".into())])),
+            default: None,
+        };
+        assert_eq!(choose(&terrain, &[0, 1, 2, 3, 4, 5, 6, 7]).as_deref(), Some("full"));
+        assert_eq!(choose(&terrain, &[0]), None);
+        terrain.default = Some("single".into());
+        assert_eq!(choose(&terrain, &[0]).as_deref(), Some("single"));
+    }
+}
@@ -1,6 +1,7 @@
 use immutable_string::ImmutableString;
 
 use hydro_common::pos::TilePosition;
+use hydro_common::TileOrientation;
 
 use crate::lua::Position;
 
@@ -101,6 +102,85 @@ impl AABB {
         (AABB { x: self.x + (vx * entry_time), y: self.y + (vy * entry_time), w: self.w, h: self.h }, collision_time)
     }
 }
+#[derive(Clone)]
+pub enum TileCollisionShape {
+    Box(AABB),
+    Platform(AABB),
+    Polygon(Vec<(f64, f64)>),
+}
+impl TileCollisionShape {
+    pub fn full() -> Self {
+        TileCollisionShape::Box(AABB { x: 0., y: 0., w: 1., h: 1. })
+    }
+    pub fn slope(from: f64, to: f64, ceiling: bool) -> Self {
+        TileCollisionShape::Polygon(if ceiling {
+            vec![(0., 0.), (1., 0.), (1., to), (0., from)]
+        } else {
+            vec![(0., 1. - from), (1., 1. - to), (1., 1.), (0., 1.)]
+        })
+    }
+    /// Moves `aabb` into the unrotated space of the tile, undoing the vertical, horizontal and diagonal flips in that order.
+    fn to_local(tile: TilePosition, orientation: TileOrientation, aabb: &AABB) -> AABB {
+        let mut local = AABB {
+            x: aabb.x - tile.x as f64,
+            y: aabb.y - tile.y as f64,
+            w: aabb.w,
+            h: aabb.h,
+        };
+        if orientation.flip_vertical() {
+            local.y = 1. - local.y - local.h;
+        }
+        if orientation.flip_horizontal() {
+            local.x = 1. - local.x - local.w;
+        }
+        if orientation.flip_diagonal() {
+            local = AABB { x: local.y, y: local.x, w: local.h, h: local.w };
+        }
+        local
+    }
+    pub fn collides(&self, tile: TilePosition, orientation: TileOrientation, aabb: &AABB, previous: Option<&AABB>) -> bool {
+        let local = TileCollisionShape::to_local(tile, orientation, aabb);
+        match self {
+            TileCollisionShape::Box(shape) => shape.collides(local),
+            TileCollisionShape::Platform(shape) => match previous {
+                //one-way platforms only stop things falling onto them from above, in the tile's own orientation
+                Some(previous) => {
+                    let previous = TileCollisionShape::to_local(tile, orientation, previous);
+                    local.y > previous.y
+                        && previous.y + previous.h <= shape.y + 0.0001
+                        && shape.collides(local)
+                }
+                None => false,
+            },
+            TileCollisionShape::Polygon(points) => {
+                let corners = [
+                    (local.x, local.y),
+                    (local.x + local.w, local.y),
+                    (local.x + local.w, local.y + local.h),
+                    (local.x, local.y + local.h),
+                ];
+                let mut axes = vec![(1., 0.), (0., 1.)];
+                for i in 0..points.len() {
+                    let (a, b) = (points[i], points[(i + 1) % points.len()]);
+                    if a != b {
+                        axes.push((a.1 - b.1, b.0 - a.0));
+                    }
+                }
+                axes.into_iter().all(|axis| {
+                    let (aabb_min, aabb_max) = project(&corners, axis);
+                    let (shape_min, shape_max) = project(points, axis);
+                    aabb_min < shape_max && shape_min < aabb_max
+                })
+            }
+        }
+    }
+}
+fn project(points: &[(f64, f64)], axis: (f64, f64)) -> (f64, f64) {
+    points.iter().fold((f64::INFINITY, -f64::INFINITY), |(min, max), point| {
+        let projected = point.0 * axis.0 + point.1 * axis.1;
+        (min.min(projected), max.max(projected))
+    })
+}
 pub struct AABBTileIterator {
     x_start: i32,
     x: i32,
@@ -122,4 +202,67 @@ impl Iterator for AABBTileIterator {
         }
         return_value
     }
-}
\ No newline at end of file
+}
+/// Reads width and height from the IHDR chunk of a PNG file.
+pub fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < 24 || &data[1..4] != b"PNG" || &data[12..16] != b"IHDR" {
+        return None;
+    }
+    Some((u32::from_be_bytes(data[16..20].try_into().ok()?), u32::from_be_bytes(data[20..24].try_into().ok()?)))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_aabb_eq(actual: AABB, expected: AABB) {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(actual.x, expected.x) && close(actual.y, expected.y) && close(actual.w, expected.w) && close(actual.h, expected.h),
+            "({}, {}, {}, {}) != ({}, {}, {}, {})", actual.x, actual.y, actual.w, actual.h, expected.x, expected.y, expected.w, expected.h);
+    }
+    /// Places a box given in the tile's own space into the world, applying the flips in Tiled's order.
+    fn to_world(tile: TilePosition, orientation: TileOrientation, local: AABB) -> AABB {
+        let mut world = local;
+        if orientation.flip_diagonal() {
+            world = AABB { x: world.y, y: world.x, w: world.h, h: world.w };
+        }
+        if orientation.flip_horizontal() {
+            world.x = 1. - world.x - world.w;
+        }
+        if orientation.flip_vertical() {
+            world.y = 1. - world.y - world.h;
+        }
+        AABB { x: world.x + tile.x as f64, y: world.y + tile.y as f64, ..world }
+    }
+
+    #[test]
+    fn to_local_moves_into_the_tile() {
+        let local = TileCollisionShape::to_local(TilePosition { x: 3, y: -2 }, TileOrientation::default(), &AABB { x: 3.25, y: -1.5, w: 0.5, h: 0.25 });
+        assert_aabb_eq(local, AABB { x: 0.25, y: 0.5, w: 0.5, h: 0.25 });
+    }
+    #[test]
+    fn to_local_mirrors_flipped_tiles() {
+        let tile = TilePosition { x: 0, y: 0 };
+        let aabb = AABB { x: 0.1, y: 0.2, w: 0.3, h: 0.4 };
+        assert_aabb_eq(TileCollisionShape::to_local(tile, TileOrientation(TileOrientation::FLIP_HORIZONTAL), &aabb), AABB { x: 0.6, y: 0.2, w: 0.3, h: 0.4 });
+        assert_aabb_eq(TileCollisionShape::to_local(tile, TileOrientation(TileOrientation::FLIP_VERTICAL), &aabb), AABB { x: 0.1, y: 0.4, w: 0.3, h: 0.4 });
+        assert_aabb_eq(TileCollisionShape::to_local(tile, TileOrientation(TileOrientation::FLIP_DIAGONAL), &aabb), AABB { x: 0.2, y: 0.1, w: 0.4, h: 0.3 });
+    }
+    #[test]
+    fn to_local_undoes_every_orientation() {
+        let tile = TilePosition { x: -4, y: 7 };
+        let local = AABB { x: 0.1, y: 0.2, w: 0.3, h: 0.4 };
+        for flags in 0..8 {
+            let orientation = TileOrientation(flags);
+            assert_aabb_eq(TileCollisionShape::to_local(tile, orientation, &to_world(tile, orientation, local)), local);
+        }
+    }
+    #[test]
+    fn rotated_slope_collides_on_its_turned_side() {
+        let tile = TilePosition { x: 0, y: 0 };
+        //a floor slope rising to the right, turned a quarter clockwise, fills the lower left half of the tile
+        let slope = TileCollisionShape::slope(0., 1., false);
+        let orientation = TileOrientation::from_rotation(1, false, false);
+        assert!(slope.collides(tile, orientation, &AABB { x: 0.05, y: 0.9, w: 0.1, h: 0.05 }, None));
+        assert!(!slope.collides(tile, orientation, &AABB { x: 0.85, y: 0.05, w: 0.1, h: 0.05 }, None));
+    }
+}